@@ -5,8 +5,10 @@
 
 use std::process::Command;
 use std::fs;
-use std::io::{self, Write};
-use std::path::Path;
+use std::io::{self, BufRead, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 // ============================================================================
 // TEN POD - High-Performance VR Gaming VM Manager
@@ -16,67 +18,257 @@ use std::path::Path;
 
 struct TenPod {
     gpu_pci: String,
-    audio_pci: String,
-    memory_gb: u32,
-    cpu_cores: String,
+    /// Every function of the GPU's slot (video, HDMI audio, USB-C controllers), function 0 first
+    gpu_functions: Vec<PciDevice>,
+    config: Config,
+    gpu_vendor: GpuVendor,
+    /// Another GPU of the same vendor stays with the host, so its driver can't be blacklisted
+    host_uses_vendor_driver: bool,
+    /// Selected GPU is an AMD card affected by the reset bug
+    needs_vendor_reset: bool,
+    /// Allow passing through the GPU the host console is using
+    force: bool,
+    /// Print what would be done instead of doing it
+    dry_run: bool,
+    pci: PciBus,
+    cpus: CpuTopology,
+    hugepages: HugePages,
+    memory: HostMemory,
+}
+
+/// GPU vendors Ten Pod knows how to pass through
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum GpuVendor {
+    Nvidia,
+    Amd,
+    Intel,
+}
+
+impl GpuVendor {
+    fn from_pci_id(id: &str) -> Option<Self> {
+        match id {
+            "10de" => Some(GpuVendor::Nvidia),
+            "1002" => Some(GpuVendor::Amd),
+            "8086" => Some(GpuVendor::Intel),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            GpuVendor::Nvidia => "NVIDIA",
+            GpuVendor::Amd => "AMD",
+            GpuVendor::Intel => "Intel",
+        }
+    }
+
+    /// Host drivers that must not grab the card before vfio-pci does
+    fn host_drivers(&self) -> &'static [&'static str] {
+        match self {
+            GpuVendor::Nvidia => &["nvidia", "nouveau"],
+            GpuVendor::Amd => &["amdgpu", "radeon"],
+            GpuVendor::Intel => &["i915", "xe"],
+        }
+    }
+
+    /// Where the guest gets its drivers from
+    fn driver_url(&self) -> &'static str {
+        match self {
+            GpuVendor::Nvidia => "https://www.nvidia.com/Download/index.aspx",
+            GpuVendor::Amd => "https://www.amd.com/en/support",
+            GpuVendor::Intel => "https://www.intel.com/content/www/us/en/download-center/home.html",
+        }
+    }
+}
+
+/// AMD GPU families that don't reset cleanly between VM boots (the "reset bug"),
+/// all of which the vendor-reset module knows how to handle
+fn amd_needs_vendor_reset(device_id: &str) -> bool {
+    let id = match u16::from_str_radix(device_id, 16) {
+        Ok(id) => id,
+        Err(_) => return false,
+    };
+
+    matches!(id,
+        0x67c0..=0x67ff | 0x6980..=0x699f // Polaris 10/11/12
+        | 0x6860..=0x687f | 0x66a0..=0x66af // Vega 10/20
+        | 0x7310..=0x731f | 0x7340..=0x734f | 0x7360..=0x7362 // Navi 10/14/12
+    )
+}
+
+/// A GPU found on the PCI bus
+#[derive(Clone)]
+struct GpuCandidate {
+    vendor: GpuVendor,
+    device_id: String,
+    model: String,
+    address: String,
+    driver: Option<String>,
+    /// Whether this GPU drives the boot framebuffer (the host console)
+    boot_vga: bool,
+    /// Integrated graphics on the root bus (never a sensible passthrough target)
+    integrated: bool,
+}
+
+impl GpuCandidate {
+    fn describe(&self) -> String {
+        format!(
+            "{}  {}  (driver: {}){}",
+            self.address,
+            self.model,
+            self.driver.as_deref().unwrap_or("none"),
+            if self.boot_vga { " (host display)" } else if self.integrated { " (integrated)" } else { "" }
+        )
+    }
 }
 
 impl TenPod {
-    fn new() -> Self {
+    fn new(config: Config) -> Self {
         Self {
             gpu_pci: String::new(),
-            audio_pci: String::new(),
-            memory_gb: 16, // Recommended for VR
-            cpu_cores: "4-7".to_string(), // Adjust based on your CPU
+            gpu_functions: Vec::new(),
+            config,
+            gpu_vendor: GpuVendor::Nvidia,
+            host_uses_vendor_driver: false,
+            needs_vendor_reset: false,
+            force: false,
+            dry_run: false,
+            pci: PciBus::system(),
+            cpus: CpuTopology::system(),
+            hugepages: HugePages::system(),
+            memory: HostMemory::system(),
         }
     }
 
-    /// Detects both the NVIDIA Video and Audio components (crucial for stability)
-    fn detect_hardware(&mut self) -> Result<(), String> {
-        println!("🔍 Scanning for NVIDIA Hardware...");
+    /// Every NVIDIA, AMD or Intel GPU in the system that could be passed through
+    fn detect_gpus(&self) -> Result<Vec<GpuCandidate>, String> {
+        let devices = self.pci.devices();
+        if devices.is_empty() {
+            return Err(format!("No PCI devices found under {}", self.pci.devices_dir().display()));
+        }
+
+        let gpus = devices.iter()
+        .filter(|d| d.is_display())
+        .filter_map(|d| {
+            let vendor = GpuVendor::from_pci_id(&d.vendor)?;
+            Some(GpuCandidate {
+                vendor,
+                device_id: d.device.clone(),
+                model: lspci_name(&d.address)
+                .unwrap_or_else(|| format!("{} GPU [{}]", vendor.name(), d.id())),
+                address: d.address.clone(),
+                driver: d.driver.clone(),
+                boot_vga: d.boot_vga,
+                integrated: d.address.starts_with("0000:00:"),
+            })
+        })
+        .collect();
 
-        let output = Command::new("lspci")
-        .arg("-nn")
-        .output()
-        .map_err(|e| format!("lspci failed: {}. Is pciutils installed?", e))?;
+        Ok(gpus)
+    }
+
+    /// Pick the GPU to pass through: the configured one, the user's choice (interactive),
+    /// or the only GPU that isn't driving the host console.
+    fn select_gpu(&self, gpus: &[GpuCandidate], interactive: bool) -> Result<GpuCandidate, String> {
+        if gpus.is_empty() {
+            return Err("No NVIDIA, AMD or Intel GPU found! Make sure your GPU is properly seated.".to_string());
+        }
 
-        let lspci_output = String::from_utf8_lossy(&output.stdout);
-        let mut base_addr = String::new();
+        let chosen = if let Some(wanted) = &self.config.gpu {
+            gpus.iter().find(|g| g.address == *wanted)
+            .ok_or_else(|| format!("Configured GPU {} not found (check gpu.address in your config)", wanted))?
+        } else if gpus.len() == 1 {
+            &gpus[0]
+        } else if interactive {
+            println!("\nMultiple GPUs found. Which one should be passed through to the VM?");
+            for (i, gpu) in gpus.iter().enumerate() {
+                println!("  {}) {}", i + 1, gpu.describe());
+            }
+            print!("Select GPU [1-{}]: ", gpus.len());
+            io::stdout().flush().ok();
 
-        for line in lspci_output.lines() {
-            if (line.contains("[10de:") || line.contains("NVIDIA")) &&
-                (line.contains("VGA") || line.contains("3D")) {
-                    let pci_addr = line.split_whitespace().next()
-                    .ok_or("Could not parse PCI address")?;
-                    self.gpu_pci = format!("0000:{}", pci_addr);
-                    base_addr = pci_addr.split(':').next().unwrap_or("").to_string();
+            let mut response = String::new();
+            io::stdin().read_line(&mut response).ok();
 
-                    // Extract GPU name
-                    let gpu_name = line.split(':').skip(2).collect::<Vec<_>>().join(":").trim().to_string();
-                    println!("✓ Found GPU: {}", gpu_name);
-                    println!("  PCI Address: {}", self.gpu_pci);
+            let choice = response.trim().parse::<usize>().ok()
+            .and_then(|n| n.checked_sub(1))
+            .and_then(|i| gpus.get(i))
+            .ok_or("No GPU selected")?;
 
-                    // Check current driver
-                    self.check_driver_status(&pci_addr.replace(".", ""));
-                }
-        }
+            println!("💡 To always use this GPU, add to your config:  [gpu] address = {}", choice.address);
+            choice
+        } else {
+            // Without a choice, only an unambiguous candidate will do
+            let spare: Vec<&GpuCandidate> = gpus.iter().filter(|g| !g.boot_vga && !g.integrated).collect();
+            let on_vfio: Vec<&GpuCandidate> = spare.iter().cloned()
+            .filter(|g| g.driver.as_deref() == Some("vfio-pci"))
+            .collect();
 
-        // Find matching audio device (same bus as GPU)
-        for line in lspci_output.lines() {
-            if line.contains(&base_addr) && line.contains("Audio device") {
-                let pci_addr = line.split_whitespace().next()
-                .ok_or("Could not parse audio PCI address")?;
-                self.audio_pci = format!("0000:{}", pci_addr);
-                println!("✓ Found GPU Audio: {}", self.audio_pci);
+            if spare.len() == 1 {
+                spare[0]
+            } else if on_vfio.len() == 1 {
+                on_vfio[0]
+            } else {
+                let list: Vec<String> = gpus.iter().map(|g| format!("  {}", g.describe())).collect();
+                return Err(format!(
+                    "Multiple GPUs found, choose one with --gpu <address> or gpu.address in your config:\n{}",
+                    list.join("\n")
+                ));
             }
+        };
+
+        if chosen.boot_vga && !self.force {
+            return Err(format!(
+                "GPU {} drives the host console (boot framebuffer).\n\
+Passing it through will leave Linux without a display until the VM exits.\n\
+Choose another GPU with --gpu, or re-run with --force if this is intentional.",
+                chosen.address
+            ));
+        }
+
+        Ok(chosen.clone())
+    }
+
+    /// Detects the GPU and all its companion functions (crucial for stability)
+    ///
+    /// Progress goes to stderr so `export-xml -` and `start --print-cmd` keep stdout clean.
+    fn detect_hardware(&mut self, interactive: bool) -> Result<(), String> {
+        eprintln!("🔍 Scanning for GPU Hardware...");
+
+        let gpus = self.detect_gpus()?;
+        for gpu in &gpus {
+            eprintln!("✓ Found GPU: {}", gpu.describe());
+        }
+
+        let gpu = self.select_gpu(&gpus, interactive)?;
+        self.gpu_pci = gpu.address.clone();
+        self.gpu_vendor = gpu.vendor;
+        eprintln!("  Selected: {}", gpu.model);
+        eprintln!("  PCI Address: {}", self.gpu_pci);
+
+        if gpu.vendor == GpuVendor::Amd && amd_needs_vendor_reset(&gpu.device_id) {
+            self.needs_vendor_reset = true;
+            eprintln!("  ⚠️  This AMD GPU is affected by the reset bug: it may not come back after the");
+            eprintln!("     VM shuts down without a host reboot. Install the vendor-reset module and set");
+            eprintln!("     [gpu] vendor_reset = true to let Ten Pod use it.");
         }
 
-        if self.gpu_pci.is_empty() {
-            return Err("No NVIDIA GPU found! Make sure your GPU is properly seated.".to_string());
+        // The host keeps its own GPU; only blacklist drivers nothing else needs
+        self.host_uses_vendor_driver = gpus.iter()
+        .any(|g| g.address != gpu.address && g.vendor == gpu.vendor);
+
+        // Check current driver
+        self.check_driver_status(&self.gpu_pci);
+
+        // Every function in the GPU's slot belongs to the card and goes to the VM with it
+        self.gpu_functions = self.pci.slot_functions(&self.gpu_pci);
+
+        for function in self.gpu_functions.iter().filter(|f| f.address != self.gpu_pci) {
+            eprintln!("✓ Found GPU {}: {}", function.role(), function.address);
         }
 
-        if self.audio_pci.is_empty() {
-            println!("⚠️  GPU Audio device not found (some GPUs don't have it)");
+        if !self.gpu_functions.iter().any(|f| f.is_audio()) {
+            eprintln!("⚠️  GPU Audio device not found (some GPUs don't have it)");
         }
 
         Ok(())
@@ -84,27 +276,24 @@ impl TenPod {
 
     /// Check what driver is currently using the GPU
     fn check_driver_status(&self, pci_addr: &str) {
-        let driver_path = format!("/sys/bus/pci/devices/0000:{}/driver", pci_addr);
-
-        if let Ok(link) = fs::read_link(&driver_path) {
-            let driver_name = link.file_name()
-            .and_then(|n| n.to_str())
-            .unwrap_or("unknown");
+        let driver = self.pci.device(pci_addr).and_then(|d| d.driver);
 
+        if let Some(driver_name) = driver.as_deref() {
             match driver_name {
-                "nvidia" => println!("  Current driver: nvidia (will be unbound after reboot)"),
-                "nouveau" => println!("  Current driver: nouveau (will be unbound after reboot)"),
-                "vfio-pci" => println!("  Current driver: vfio-pci (already configured!)"),
-                _ => println!("  Current driver: {}", driver_name),
+                "vfio-pci" => eprintln!("  Current driver: vfio-pci (already configured!)"),
+                d if self.gpu_vendor.host_drivers().contains(&d) => {
+                    eprintln!("  Current driver: {} (will be unbound after reboot)", d)
+                }
+                _ => eprintln!("  Current driver: {}", driver_name),
             }
         } else {
-            println!("  Current driver: none (perfect for passthrough)");
+            eprintln!("  Current driver: none (perfect for passthrough)");
         }
     }
 
     /// Detect VR headsets connected via USB
     fn detect_headsets(&self) -> Vec<(String, String, String)> {
-        println!("\n🎧 Scanning for VR headsets...");
+        eprintln!("\n🎧 Scanning for VR headsets...");
 
         let headset_db = vec![
             ("28de", "2012", "Valve Index"),
@@ -121,7 +310,7 @@ impl TenPod {
 
         let output = Command::new("lsusb").output();
         if output.is_err() {
-            println!("  ⚠️  lsusb not available, install usbutils for headset detection");
+            eprintln!("  ⚠️  lsusb not available, install usbutils for headset detection");
             return vec![];
         }
 
@@ -133,32 +322,24 @@ impl TenPod {
             for (vendor, product, name) in &headset_db {
                 if line.contains(vendor) && line.contains(product) {
                     found.push((vendor.to_string(), product.to_string(), name.to_string()));
-                    println!("✓ Found: {}", name);
+                    eprintln!("✓ Found: {}", name);
                 }
             }
         }
 
         if found.is_empty() {
-            println!("  No VR headset detected (plug it in before starting VM)");
+            eprintln!("  No VR headset detected (plug it in before starting VM)");
         }
 
         found
     }
 
-    /// Get VFIO hardware IDs for both GPU and Audio
+    /// Get VFIO hardware IDs for every function of the GPU
     fn get_vfio_ids(&self) -> String {
         let mut ids = Vec::new();
-        for addr in [&self.gpu_pci, &self.audio_pci] {
-            if addr.is_empty() { continue; }
-            let out = Command::new("lspci")
-            .args(["-n", "-s", &addr.replace("0000:", "")])
-            .output()
-            .ok();
-            if let Some(o) = out {
-                let s = String::from_utf8_lossy(&o.stdout);
-                if let Some(id) = s.split_whitespace().nth(2) {
-                    ids.push(id.to_string());
-                }
+        for function in &self.gpu_functions {
+            if !ids.contains(&function.id()) {
+                ids.push(function.id());
             }
         }
         ids.join(",")
@@ -170,19 +351,16 @@ impl TenPod {
 
         // Check IOMMU
         print!("  • IOMMU enabled... ");
-        let iommu = Command::new("sh")
-        .arg("-c")
-        .arg("sudo dmesg 2>/dev/null | grep -i iommu | head -1")
-        .output();
+        let groups = self.pci.iommu_group_count();
 
-        if iommu.is_ok() && !iommu.unwrap().stdout.is_empty() {
-            println!("✓");
+        if groups > 0 {
+            println!("✓ ({} groups)", groups);
         } else {
             println!("✗");
             return Err(
                 "IOMMU not enabled!\n\
-Add to /etc/default/grub: GRUB_CMDLINE_LINUX=\"intel_iommu=on\" (or amd_iommu=on)\n\
-Then run: sudo grub2-mkconfig -o /boot/grub2/grub.cfg && sudo reboot".to_string()
+Run: ./tenpod.rs kernel-args (adds intel_iommu=on or amd_iommu=on to your boot loader)\n\
+Then reboot. If it still fails, enable VT-d / AMD-Vi (IOMMU) in your BIOS.".to_string()
             );
         }
 
@@ -208,24 +386,201 @@ Enable VT-x (Intel) or AMD-V (AMD) in your BIOS settings.".to_string()
 
         // Check GPU driver status
         print!("  • GPU status... ");
-        let driver_path = format!("/sys/bus/pci/devices/{}/driver", self.gpu_pci);
-
-        if let Ok(link) = fs::read_link(&driver_path) {
-            let driver = link.file_name()
-            .and_then(|n| n.to_str())
-            .unwrap_or("unknown");
+        let driver = self.pci.device(&self.gpu_pci).and_then(|d| d.driver);
 
+        if let Some(driver) = driver.as_deref() {
             match driver {
                 "vfio-pci" => println!("✓ (bound to vfio-pci, ready!)"),
+                _ if self.config.auto_bind => println!("✓ (using {}, will be bound to vfio-pci at start)", driver),
                 _ => println!("⚠️  (currently using {}, will switch after reboot)", driver),
             }
         } else {
             println!("✓ (no driver, ready for passthrough)");
         }
 
+        // Report resource problems too, not just the first failure
+        let errors: Vec<String> = vec![self.check_iommu_group(), self.check_resources()]
+        .into_iter()
+        .filter_map(Result::err)
+        .collect();
+        if !errors.is_empty() {
+            return Err(errors.join("\n\n"));
+        }
+
         Ok(())
     }
 
+    /// Make sure the VM leaves the host enough memory and CPUs to keep running
+    fn check_resources(&self) -> Result<(), String> {
+        let gb = |kb: u64| kb as f64 / (1024.0 * 1024.0);
+        let mut problems = Vec::new();
+
+        print!("  • Memory... ");
+        match self.memory.kb("MemTotal") {
+            Some(total) => {
+                let guest = self.config.memory_gb as u64 * 1024 * 1024;
+                let host = total.saturating_sub(guest);
+                if host < self.config.host_memory_gb as u64 * 1024 * 1024 {
+                    println!("✗");
+                    problems.push(format!(
+                        "vm.memory_gb = {} leaves the host {:.1}GB of {:.1}GB, less than host.memory_gb = {}",
+                        self.config.memory_gb, gb(host), gb(total), self.config.host_memory_gb
+                    ));
+                } else {
+                    println!("✓ ({}GB for the VM, {:.1}GB left for the host)", self.config.memory_gb, gb(host));
+
+                    // Memory in use right now has to be reclaimed before the guest fits
+                    if let Some(available) = self.memory.kb("MemAvailable").filter(|&a| a < guest) {
+                        println!("    ⚠️  Only {:.1}GB is available right now, close some applications first", gb(available));
+                    }
+                }
+            }
+            None => println!("? (could not read /proc/meminfo)"),
+        }
+
+        print!("  • CPUs... ");
+        match self.cpu_plan() {
+            Ok(plan) => {
+                let offline: Vec<u32> = plan.vcpus.iter().copied().filter(|c| !self.cpus.online.contains(c)).collect();
+                let host = self.cpus.online.iter().filter(|c| !plan.vcpus.contains(c)).count();
+                if !offline.is_empty() {
+                    println!("✗");
+                    problems.push(format!(
+                        "vm.cpu_cores uses CPUs {} but this host only has CPUs {}",
+                        format_cpu_list(&offline), format_cpu_list(&self.cpus.online)
+                    ));
+                } else if host < self.config.host_cpus as usize {
+                    println!("✗");
+                    problems.push(format!(
+                        "{} vCPUs leave the host {} of {} CPUs, less than host.cpus = {}",
+                        plan.vcpus.len(), host, self.cpus.online.len(), self.config.host_cpus
+                    ));
+                } else {
+                    println!("✓ ({} vCPUs, {} CPUs left for the host)", plan.vcpus.len(), host);
+                }
+            }
+            Err(e) => {
+                println!("✗");
+                problems.push(e);
+            }
+        }
+
+        if problems.is_empty() {
+            return Ok(());
+        }
+
+        let (memory_gb, cores) = match self.suggested_resources() {
+            Ok(size) => size,
+            Err(e) => return Err(format!("{}\n\nNo VM size fits this machine: {}", problems.join("\n"), e)),
+        };
+        Err(format!(
+            "{}\n\nSuggested for this machine ({:.0}GB RAM, {} CPUs):\n  [vm]\n  memory_gb = {}\n  cores = {}\n  cpu_cores = auto\n\
+Add them to your config, or try: ./tenpod.rs start --set vm.memory_gb={} --set vm.cores={} --set vm.cpu_cores=auto",
+            problems.join("\n"),
+            gb(self.memory.kb("MemTotal").unwrap_or(0)),
+            self.cpus.online.len(),
+            memory_gb, cores, memory_gb, cores
+        ))
+    }
+
+    /// A VM size that fits this machine: half the RAM (4GB to 16GB) while keeping host.memory_gb,
+    /// and up to 8 whole cores while keeping host.cpus. Err says why not even the smallest fits.
+    fn suggested_resources(&self) -> Result<(u32, u32), String> {
+        // MemTotal is a little under the installed RAM, round it to whole GB
+        let total_gb = ((self.memory.kb("MemTotal").unwrap_or(0) + 512 * 1024) / (1024 * 1024)) as u32;
+        let memory_gb = (total_gb / 2).clamp(4, 16).min(total_gb.saturating_sub(self.config.host_memory_gb));
+        if memory_gb < 4 {
+            return Err(format!(
+                "Windows 11 needs at least 4GB, and {}GB of RAM can't spare that while keeping host.memory_gb = {}",
+                total_gb, self.config.host_memory_gb
+            ));
+        }
+
+        let online = self.cpus.online.len();
+        let cores = (1..=8).rev().find(|&cores| {
+            self.cpus.select_cores(cores, None)
+            .map(|(chosen, _)| online - chosen.iter().map(|c| c.cpus.len()).sum::<usize>() >= self.config.host_cpus as usize)
+            .unwrap_or(false)
+        });
+        match cores {
+            Some(cores) => Ok((memory_gb, cores as u32)),
+            None => Err(format!(
+                "not even one core can go to the VM while keeping host.cpus = {} of {} CPUs",
+                self.config.host_cpus, online
+            )),
+        }
+    }
+
+    /// Make sure everything sharing the GPU's IOMMU group can go to the VM with it
+    fn check_iommu_group(&self) -> Result<(), String> {
+        print!("  • IOMMU group isolation... ");
+
+        let group = match self.pci.device(&self.gpu_pci).and_then(|d| d.iommu_group) {
+            Some(group) => group,
+            None => {
+                println!("✗");
+                return Err(format!("GPU {} has no IOMMU group (is IOMMU enabled?)", self.gpu_pci));
+            }
+        };
+
+        let members: Vec<PciDevice> = self.pci.iommu_group_devices(&group).iter()
+        .filter_map(|addr| self.pci.device(addr))
+        .collect();
+
+        // Functions of the GPU's own slot are passed through together; bridges stay with the host
+        let slot = pci_slot(&self.gpu_pci);
+        let blockers: Vec<&PciDevice> = members.iter()
+        .filter(|d| pci_slot(&d.address) != slot && !d.is_bridge())
+        .collect();
+
+        if blockers.is_empty() {
+            println!("✓ (group {})", group);
+        } else {
+            println!("✗ (group {})", group);
+        }
+
+        println!("      GPU {} is in IOMMU group {}", self.gpu_pci, group);
+        for function in self.gpu_functions.iter().filter(|f| f.address != self.gpu_pci) {
+            match &function.iommu_group {
+                Some(g) => println!("      {} {} is in IOMMU group {}", function.role(), function.address, g),
+                None => println!("      {} {} has no IOMMU group", function.role(), function.address),
+            }
+        }
+
+        for device in &members {
+            let role = if device.address == self.gpu_pci {
+                "GPU"
+            } else if pci_slot(&device.address) == slot {
+                "GPU function"
+            } else if device.is_bridge() {
+                "bridge, stays with host"
+            } else {
+                "⚠️  shared"
+            };
+            println!("        {} [{}] {}", device.address, role, pci_device_name(device));
+        }
+
+        if blockers.is_empty() {
+            return Ok(());
+        }
+
+        let list: Vec<String> = blockers.iter()
+        .map(|d| format!("  {} {}", d.address, pci_device_name(d)))
+        .collect();
+        Err(format!(
+            "The GPU's IOMMU group {} also contains devices that aren't part of the GPU:\n{}\n\n\
+VFIO can only pass through a whole group, and these devices would be taken from the host too.\n\
+Options:\n\
+• Move the GPU to another PCIe slot (usually the top, CPU-attached x16 slot is isolated)\n\
+• Check for a BIOS update or an \"ACS\" option in your BIOS\n\
+• Use a kernel with the ACS override patch (Nobara and linux-zen ship it) and add\n\
+pcie_acs_override=downstream,multifunction to the kernel command line.\n\
+This disables isolation between those devices; only use it if you accept that.",
+            group,
+            list.join("\n")
+        ))
+    }
+
     /// One-click installer
     fn install(&self) -> Result<(), String> {
         println!("\n🚀 TEN POD ONE-CLICK INSTALLER\n");
@@ -237,6 +592,10 @@ Enable VT-x (Intel) or AMD-V (AMD) in your BIOS settings.".to_string()
         println!("  • User permissions for VM management");
         println!("  • 100GB VM disk image\n");
 
+        if self.dry_run {
+            return self.print_package_plan();
+        }
+
         print!("Continue? (y/n): ");
         io::stdout().flush().ok();
 
@@ -247,25 +606,100 @@ Enable VT-x (Intel) or AMD-V (AMD) in your BIOS settings.".to_string()
             return Err("Installation cancelled by user".to_string());
         }
 
+        // Record every change as we go, and keep the record even if a step fails
+        let mut manifest = Manifest::load();
+        let result = self.apply_install(&mut manifest);
+
+        match manifest.save() {
+            Ok(()) => println!("\n📝 Install manifest written to {}", MANIFEST_PATH),
+            Err(e) => println!("\n⚠️  Could not write install manifest: {}", e),
+        }
+
+        result?;
+
+        // Boot-time isolation of the vCPU cores (isolation.kernel_args)
+        if self.config.isolate_kernel_args {
+            println!("\n🧱 Kernel CPU isolation");
+            self.kernel_args(false, true)?;
+        }
+
+        // Final instructions
+        println!("\n✅ Installation Complete!\n");
+        println!("📋 Next Steps:");
+        println!("  1. REBOOT your system for VFIO changes to take effect");
+        println!("  2. Download the Windows 11 ISO to: {}", self.config.iso_path);
+        println!("     Get it from: https://www.microsoft.com/software-download/windows11");
+        println!("  3. Plug in your VR headset");
+        println!("  4. Run: ./tenpod.rs start");
+        println!("\n⚠️  IMPORTANT: Reboot is required!");
+
+        Ok(())
+    }
+
+    /// Show what the package step would run, without changing anything
+    fn print_package_plan(&self) -> Result<(), String> {
+        let pm = PackageManager::detect()
+        .ok_or("Unknown distribution: no supported package manager found in /etc/os-release")?;
+
+        println!("Distribution package manager: {}", pm.name());
+        for requirement in Requirement::ALL {
+            println!("  {:<10} {}", requirement.name(), pm.packages(*requirement).join(" "));
+        }
+
+        let missing = pm.missing_packages(Requirement::ALL);
+        println!();
+        if missing.is_empty() {
+            println!("All packages are already installed, nothing to run.");
+        } else {
+            let command = pm.install_command(&missing);
+            println!("Would run:");
+            println!("  pkexec {}", command.iter().map(|a| shell_quote(a)).collect::<Vec<_>>().join(" "));
+        }
+
+        println!("\n(dry run, nothing was changed)");
+        Ok(())
+    }
+
+    /// Installer steps, each recorded in the manifest
+    fn apply_install(&self, manifest: &mut Manifest) -> Result<(), String> {
         // Step 1: Install packages
         println!("\n[1/6] 📦 Installing QEMU/KVM packages...");
-        let packages = vec![
-            "qemu-kvm", "libvirt", "virt-manager", "bridge-utils",
-            "libvirt-daemon-config-network", "virt-install"
-        ];
+        match PackageManager::detect() {
+            Some(pm) => {
+                // Only packages that were missing beforehand belong to Ten Pod
+                let missing = pm.missing_packages(Requirement::ALL);
 
-        let install_result = Command::new("pkexec")
-        .arg("dnf")
-        .arg("install")
-        .arg("-y")
-        .args(&packages)
-        .status();
+                if missing.is_empty() {
+                    println!("      ✓ All packages already installed");
+                } else {
+                    println!("      Using {}: {}", pm.name(), missing.join(" "));
+                    let command = pm.install_command(&missing);
 
-        match install_result {
-            Ok(_) => println!("      ✓ Packages installed successfully"),
-            Err(e) => {
-                println!("      ⚠️  Package installation failed: {}", e);
-                println!("      Continuing anyway (packages may already be installed)");
+                    let install_result = Command::new("pkexec")
+                    .args(&command)
+                    .status();
+
+                    match install_result {
+                        Ok(status) if status.success() => {
+                            println!("      ✓ Packages installed successfully");
+                            for package in &missing {
+                                manifest.record(ManifestEntry::Package { name: package.to_string() });
+                            }
+                        }
+                        Ok(_) => {
+                            println!("      ⚠️  {} reported an error", pm.name());
+                            println!("      Continuing anyway (packages may already be installed)");
+                        }
+                        Err(e) => {
+                            println!("      ⚠️  Package installation failed: {}", e);
+                            println!("      Continuing anyway (packages may already be installed)");
+                        }
+                    }
+                }
+            }
+            None => {
+                println!("      ⚠️  Unknown distribution (no supported package manager in /etc/os-release)");
+                println!("      Install QEMU, OVMF, libvirt, swtpm, usbutils and pciutils yourself");
             }
         }
 
@@ -279,76 +713,132 @@ Enable VT-x (Intel) or AMD-V (AMD) in your BIOS settings.".to_string()
 
         println!("      Hardware IDs: {}", ids);
 
-        let vfio_conf = format!(
-            "# Ten Pod VFIO Configuration\noptions vfio-pci ids={}\nblacklist nvidia\nblacklist nouveau",
-            ids
-        );
+        let drivers = self.gpu_vendor.host_drivers();
+        let mut vfio_conf = format!("# Ten Pod VFIO Configuration\noptions vfio-pci ids={}\n", ids);
 
-        Command::new("pkexec")
-        .arg("sh")
-        .arg("-c")
-        .arg(format!("echo '{}' > /etc/modprobe.d/vfio.conf", vfio_conf))
-        .status()
+        if self.host_uses_vendor_driver {
+            // Blacklisting would take the host's own GPU down with it
+            println!("      Host keeps another {} GPU, relying on softdeps instead of blacklisting {}",
+                     self.gpu_vendor.name(), drivers.join("/"));
+        } else {
+            for driver in drivers {
+                vfio_conf.push_str(&format!("blacklist {}\n", driver));
+            }
+        }
+
+        manifest.write_file("/etc/modprobe.d/vfio.conf", &vfio_conf)
         .map_err(|e| format!("Failed to write vfio.conf: {}", e))?;
 
-        // Priority configuration
-        let softdep = "softdep nvidia pre: vfio-pci\nsoftdep nouveau pre: vfio-pci";
-        Command::new("pkexec")
-        .arg("sh")
-        .arg("-c")
-        .arg(format!("echo '{}' > /etc/modprobe.d/vfio-priority.conf", softdep))
-        .status()
-        .ok();
+        // Priority configuration, including the modules of the card's USB-C/UCSI functions.
+        // softdep takes module names, which differ from sysfs driver names (xhci_hcd is xhci_pci);
+        // built-in drivers have no module to order.
+        let mut softdep_modules: Vec<String> = drivers.iter().map(|d| d.to_string()).collect();
+        for function in &self.gpu_functions {
+            let module = function.driver.as_deref()
+            .filter(|driver| *driver != "vfio-pci")
+            .and_then(|driver| self.pci.driver_module(driver));
+            if let Some(module) = module {
+                if !softdep_modules.contains(&module) {
+                    softdep_modules.push(module);
+                }
+            }
+        }
 
-        // Rebuild initramfs
-        println!("      Rebuilding initramfs (may take 30-60 seconds)...");
-        Command::new("pkexec")
-        .arg("dracut")
-        .arg("-f")
-        .arg("--force")
-        .status()
-        .ok();
+        let softdep: String = softdep_modules.iter()
+        .map(|d| format!("softdep {} pre: vfio-pci\n", d))
+        .collect();
+        manifest.write_file("/etc/modprobe.d/vfio-priority.conf", &softdep).ok();
 
-        println!("      ✓ VFIO configured");
+        // AMD reset bug workaround
+        if self.needs_vendor_reset && self.config.vendor_reset {
+            let available = Command::new("modinfo").arg("vendor-reset").output()
+            .map(|o| o.status.success())
+            .unwrap_or(false);
 
-        // Step 3: Setup hugepages
-        println!("\n[3/6] 💾 Configuring hugepages for low latency...");
-        let pages = (self.memory_gb * 1024) / 2;
+            if available {
+                manifest.write_file("/etc/modules-load.d/vendor-reset.conf", "vendor-reset\n").ok();
+                println!("      ✓ vendor-reset module will load at boot");
+            } else {
+                println!("      ⚠️  vendor_reset is enabled but the vendor-reset module isn't installed");
+                println!("         Get it from https://github.com/gnif/vendor-reset (dkms)");
+            }
+        }
 
-        Command::new("pkexec")
-        .arg("sh")
-        .arg("-c")
-        .arg(format!("echo 'vm.nr_hugepages = {}' >> /etc/sysctl.conf", pages))
-        .status()
-        .ok();
+        // Load vfio-pci from the initramfs, before any GPU driver
+        let tool = InitramfsTool::detect()
+        .ok_or("No supported initramfs generator found (dracut, update-initramfs or mkinitcpio)")?;
+        println!("      Initramfs generator: {}", tool.name());
 
-        // Apply immediately
-        Command::new("pkexec")
-        .arg("sysctl")
-        .arg("-w")
-        .arg(format!("vm.nr_hugepages={}", pages))
-        .status()
-        .ok();
+        match tool.module_config() {
+            ModuleConfig::DropIn { path, contents } => manifest.write_file(path, &contents)?,
+            ModuleConfig::Lines { path } => {
+                for module in VFIO_MODULES {
+                    manifest.append_line(path, module)?;
+                }
+            }
+        }
+
+        println!("      Rebuilding initramfs (may take 30-60 seconds)...");
+        tool.regenerate()?;
+
+        match tool.verify() {
+            Ok(true) => println!("      ✓ vfio-pci is in the initramfs"),
+            Ok(false) => {
+                return Err(format!(
+                    "The rebuilt initramfs does not contain vfio-pci.\n\
+Check the {} configuration and run it by hand to see why.",
+                    tool.name()
+                ))
+            }
+            Err(e) => println!("      ⚠️  Could not verify the initramfs: {}", e),
+        }
+
+        println!("      ✓ VFIO configured");
 
-        println!("      ✓ Hugepages configured ({} pages)", pages);
+        // Step 3: Hugepages are allocated by `start` and freed when the VM exits, so nothing
+        // is reserved permanently
+        println!("\n[3/6] 💾 Checking hugepage support...");
+        if self.hugepages.supported(self.config.hugepage_kb()) {
+            println!("      ✓ {} hugepages supported, they are allocated each time the VM starts", self.config.hugepage_size);
+        } else {
+            println!("      ⚠️  This host doesn't support {} hugepages, the VM will use normal memory", self.config.hugepage_size);
+        }
 
         // Step 4: User permissions
         println!("\n[4/6] 👤 Setting up user permissions...");
         let user = std::env::var("USER").unwrap_or_else(|_| "user".to_string());
 
+        let id_output = Command::new("id").args(["-nG", &user]).output().ok();
+        let current_groups = id_output
+        .map(|o| String::from_utf8_lossy(&o.stdout).to_string())
+        .unwrap_or_default();
+
         for group in &["libvirt", "kvm", "input"] {
-            Command::new("pkexec")
+            if current_groups.split_whitespace().any(|g| g == *group) {
+                continue;
+            }
+
+            let added = Command::new("pkexec")
             .args(["usermod", "-aG", group, &user])
             .status()
-            .ok();
+            .map(|s| s.success())
+            .unwrap_or(false);
+
+            if added {
+                manifest.record(ManifestEntry::Group { user: user.clone(), group: group.to_string() });
+            }
         }
 
         println!("      ✓ User '{}' added to libvirt, kvm, input groups", user);
 
-        // Step 5: Create VM disk
+        // Step 5: Create VM disk (decide the firmware first, a disk that already exists may
+        // hold a guest installed with BIOS)
         println!("\n[5/6] 📀 Creating VM disk image...");
-        let disk_dir = "/var/lib/libvirt/images";
-        let disk_path = format!("{}/win10_tenpod.qcow2", disk_dir);
+        let firmware_type = self.firmware_type();
+        let disk_path = self.config.disk_path.clone();
+        let disk_dir = Path::new(&disk_path).parent()
+        .and_then(|p| p.to_str())
+        .unwrap_or("/var/lib/libvirt/images");
 
         Command::new("pkexec")
         .args(["mkdir", "-p", disk_dir])
@@ -361,13 +851,33 @@ Enable VT-x (Intel) or AMD-V (AMD) in your BIOS settings.".to_string()
             .status();
 
             match create_result {
-                Ok(_) => println!("      ✓ Created 100GB disk at {}", disk_path),
+                Ok(status) if status.success() => {
+                    println!("      ✓ Created 100GB disk at {}", disk_path);
+                    manifest.record(ManifestEntry::Disk { path: disk_path.clone() });
+                }
+                Ok(status) => return Err(format!("qemu-img could not create {} ({})", disk_path, status)),
                 Err(e) => return Err(format!("Failed to create disk: {}", e)),
             }
         } else {
             println!("      ✓ Disk already exists at {}", disk_path);
         }
 
+        if firmware_type == "uefi" {
+            let (firmware, nvram) = self.prepare_nvram()?;
+            println!("      ✓ UEFI firmware: {}", firmware.code);
+            println!("      ✓ UEFI variables: {}", nvram);
+            manifest.record(ManifestEntry::File {
+                path: nvram.clone(),
+                checksum: checksum(&fs::read(&nvram).unwrap_or_default()),
+                prior: None,
+            });
+        }
+
+        if self.config.tpm {
+            let tpm_dir = self.prepare_tpm_state()?;
+            println!("      ✓ TPM state: {}", tpm_dir);
+        }
+
         // Step 6: Enable libvirt service
         println!("\n[6/6] ⚡ Enabling libvirt service...");
         Command::new("pkexec")
@@ -377,153 +887,4550 @@ Enable VT-x (Intel) or AMD-V (AMD) in your BIOS settings.".to_string()
 
         println!("      ✓ libvirtd service enabled");
 
-        // Final instructions
-        println!("\n✅ Installation Complete!\n");
-        println!("📋 Next Steps:");
-        println!("  1. REBOOT your system for VFIO changes to take effect");
-        println!("  2. Download Windows 10 ISO to: /var/lib/libvirt/images/win10.iso");
-        println!("     Get it from: https://www.microsoft.com/software-download/windows10");
-        println!("  3. Plug in your VR headset");
-        println!("  4. Run: ./tenpod.rs start");
-        println!("\n⚠️  IMPORTANT: Reboot is required!");
-
         Ok(())
     }
 
-    /// Start the VM with VR optimizations
-    fn start(&self, headsets: Vec<(String, String, String)>) -> Result<(), String> {
-        println!("\n🚀 Launching Ten Pod VM (VR Optimized)...\n");
+    /// Add the IOMMU (and optionally vfio-pci.ids and CPU isolation) kernel arguments through
+    /// the boot loader
+    fn kernel_args(&self, with_vfio_ids: bool, isolate: bool) -> Result<(), String> {
+        println!("\n🐧 KERNEL COMMAND LINE\n");
 
-        let disk_path = "/var/lib/libvirt/images/win10_tenpod.qcow2";
-        let iso_path = "/var/lib/libvirt/images/win10.iso";
+        let cpu = cpu_vendor().ok_or("Could not tell Intel from AMD in /proc/cpuinfo")?;
+        let cmdline = fs::read_to_string("/proc/cmdline").unwrap_or_default();
+        println!("CPU: {}", cpu.name());
+        println!("Current: {}", cmdline.trim());
 
-        // Pre-flight checks
-        if !Path::new(disk_path).exists() {
-            return Err(format!("VM disk not found at {}\nRun: ./tenpod.rs install", disk_path));
+        let mut wanted = vec![cpu.iommu_arg().to_string(), "iommu=pt".to_string()];
+        if with_vfio_ids {
+            let ids = self.get_vfio_ids();
+            if ids.is_empty() {
+                return Err("Could not determine GPU hardware IDs".to_string());
+            }
+            wanted.push(format!("vfio-pci.ids={}", ids));
         }
 
-        let mut cmd = Command::new("taskset");
-        cmd.arg("-c").arg(&self.cpu_cores);
-        cmd.arg("qemu-system-x86_64");
-
-        // CPU & Hyper-V Enlightenments for Latency Reduction
-        cmd.args([
-            "-name", "TenPod",
-            "-machine", "type=q35,accel=kvm,kernel_irqchip=on",
-            "-cpu", "host,hv_time,hv_relaxed,hv_vapic,hv_spinlocks=0x1fff,hv_vendor_id=tenpodvr,kvm=off,+invtsc",
-            "-smp", "4,sockets=1,cores=4,threads=1",
-            "-m", &format!("{}G", self.memory_gb),
-                 "-enable-kvm",
-        ]);
-
-        // Timing Stability for smooth VR frame pacing
-        cmd.args([
-            "-rtc", "base=localtime,clock=host,driftfix=slew",
-            "-global", "kvm-pit.lost_tick_policy=delay",
-            "-no-hpet",
-        ]);
-
-        // Hugepages for reduced memory latency
-        if Path::new("/dev/hugepages").exists() {
-            cmd.args(["-mem-path", "/dev/hugepages", "-mem-prealloc"]);
-            println!("💾 Using hugepages for low-latency memory");
+        // Take the vCPU cores away from the scheduler, timer ticks and RCU callbacks
+        if isolate {
+            let cpus = format_cpu_list(&self.cpu_plan()?.vcpus);
+            println!("Isolating vCPU cores: {}", cpus);
+            wanted.push(format!("isolcpus=managed_irq,domain,{}", cpus));
+            wanted.push(format!("nohz_full={}", cpus));
+            wanted.push(format!("rcu_nocbs={}", cpus));
         }
 
-        // GPU Passthrough (Multifunction ensures Video + Audio are seen as one card)
-        println!("🎮 Passing through GPU: {}", self.gpu_pci);
-        cmd.args([
-            "-device",
-            &format!("vfio-pci,host={},multifunction=on", self.gpu_pci.replace("0000:", ""))
-        ]);
+        // Compare with what the next boot will use, so running this twice before rebooting
+        // doesn't add everything again
+        let loader = BootLoader::detect()
+        .ok_or("No supported boot loader found (grubby, GRUB, systemd-boot or kernelstub)")?;
+        let mut manifest = Manifest::load();
+        let configured = loader.configured_args(&manifest);
 
-        if !self.audio_pci.is_empty() {
-            println!("🔊 Passing through GPU Audio: {}", self.audio_pci);
-            cmd.args([
-                "-device",
-                &format!("vfio-pci,host={}", self.audio_pci.replace("0000:", ""))
-            ]);
-        }
+        let missing: Vec<String> = wanted.into_iter()
+        .filter(|arg| !configured.contains(arg))
+        .collect();
 
-        // Fast Disk I/O with virtio-scsi
-        cmd.args([
-            "-device", "virtio-scsi-pci,id=scsi0",
-            "-drive", &format!("file={},format=qcow2,if=none,id=dr1,cache=none,aio=native", disk_path),
-                 "-device", "scsi-hd,drive=dr1",
-        ]);
+        // A different value for the same key would leave two conflicting arguments
+        for arg in &missing {
+            if let Some((key, _)) = arg.split_once('=') {
+                if let Some(other) = configured.iter().find(|a| a.starts_with(&format!("{}=", key))) {
+                    return Err(format!("The boot configuration already has {}; remove it before adding {}", other, arg));
+                }
+            }
+        }
 
-        // Boot from ISO if it exists (first-time Windows install)
-        if Path::new(iso_path).exists() {
-            println!("📀 Windows ISO detected, booting from ISO for installation");
-            cmd.args(["-cdrom", iso_path, "-boot", "d"]);
+        println!("Boot loader: {}", loader.name());
+        if missing.is_empty() {
+            println!("\n✓ All arguments are already in the boot configuration");
+            return Ok(());
         }
 
-        // Enable USB 3.0 (Required for VR headsets)
-        cmd.args(["-device", "qemu-xhci,id=usb-bus-0", "-usb"]);
+        println!("Adding: {}\n", missing.join(" "));
 
-        // Auto-passthrough detected VR headsets
-        if !headsets.is_empty() {
-            for (vendor, product, name) in &headsets {
-                println!("🎧 Passing through: {}", name);
-                cmd.args([
-                    "-device",
-                    &format!("usb-host,vendorid=0x{},productid=0x{}", vendor, product)
-                ]);
-            }
-        } else {
-            println!("⚠️  No VR headset detected - plug it in and restart VM");
+        if self.dry_run {
+            loader.preview(&missing);
+            println!("\n(dry run, nothing was changed)");
+            return Ok(());
         }
 
-        // Network (for Windows updates, SteamVR downloads)
-        cmd.args([
-            "-netdev", "user,id=net0",
-            "-device", "virtio-net-pci,netdev=net0",
-        ]);
+        print!("Continue? (y/n): ");
+        io::stdout().flush().ok();
 
-        // VGA Output: None (we use physical GPU output)
-        cmd.args(["-vga", "none", "-nographic"]);
+        let mut response = String::new();
+        io::stdin().read_line(&mut response).ok();
 
-        println!("\n💻 VM Starting...");
-        println!("📺 Check your GPU's physical monitor output for Windows display");
-        println!("🎮 Once Windows boots, install NVIDIA drivers and SteamVR\n");
+        if !response.trim().to_lowercase().starts_with('y') {
+            return Err("Cancelled by user".to_string());
+        }
 
-        cmd.status()
-        .map_err(|e| format!("Failed to start VM: {}", e))?;
+        let result = loader.apply(&missing, &mut manifest);
+        manifest.save().unwrap_or_else(|e| println!("⚠️  Could not write install manifest: {}", e));
+        result?;
 
+        match loader {
+            BootLoader::Grubby | BootLoader::Kernelstub => {
+                println!("\n✅ Kernel arguments added (recorded in {} for uninstall)", MANIFEST_PATH)
+            }
+            _ => println!("\n✅ Kernel arguments added (previous files backed up in {})", BACKUP_DIR),
+        }
+        println!("⚠️  Reboot for them to take effect");
         Ok(())
     }
 
-    /// Stop the VM
-    fn stop(&self) -> Result<(), String> {
-        println!("🛑 Stopping Ten Pod VM...");
+    /// Revert everything the installer changed and hand the GPU back to Linux
+    fn uninstall(&self, remove_disk: bool) -> Result<(), String> {
+        println!("\n🗑️  TEN POD UNINSTALLER\n");
+        println!("This will undo the Ten Pod system configuration:");
+        println!("  • Remove VFIO configuration (GPU returns to its Linux driver)");
+        println!("  • Remove lines it added to shared files, such as a hugepages reservation from older versions");
+        println!("  • Rebuild initramfs");
+        if remove_disk {
+            println!("  • DELETE the VM disk image (your Windows installation!)");
+            println!("  • DELETE its UEFI variables and TPM state");
+        }
+        println!();
 
-        Command::new("pkill")
-        .arg("-f")
-        .arg("TenPod")
-        .status()
-        .ok();
+        print!("Continue? (y/n): ");
+        io::stdout().flush().ok();
 
-        println!("✓ VM stopped");
-        Ok(())
-    }
+        let mut response = String::new();
+        io::stdin().read_line(&mut response).ok();
 
-    /// Show comprehensive status
-    fn status(&self) {
-        println!("\n📊 TEN POD STATUS\n");
-        println!("GPU Configuration:");
-        println!("  Video: {}", self.gpu_pci);
-        if !self.audio_pci.is_empty() {
-            println!("  Audio: {}", self.audio_pci);
+        if !response.trim().to_lowercase().starts_with('y') {
+            return Err("Uninstall cancelled by user".to_string());
+        }
+
+        let manifest = Manifest::load();
+        if !Manifest::exists() {
+            println!("\n⚠️  No install manifest at {}, reverting the default install locations", MANIFEST_PATH);
+        }
+
+        // Step 1: Restore files Ten Pod wrote
+        println!("\n[1/5] 🔧 Removing VFIO configuration...");
+        let vms_dir = format!("{}/vms/", STATE_DIR);
+        if Manifest::exists() {
+            for entry in &manifest.entries {
+                match entry {
+                    // UEFI variables and TPM state belong to the guest, they go with the disk
+                    ManifestEntry::File { path, .. } if path.starts_with(&vms_dir) && !remove_disk => {}
+                    ManifestEntry::File { path, .. } => {
+                        if path.starts_with(SYSTEM_UNIT_DIR) {
+                            let unit = path.rsplit('/').next().unwrap_or(path);
+                            Command::new("pkexec").args(["systemctl", "disable", "--now", unit]).status().ok();
+                        }
+                        manifest.revert(entry)?;
+                        println!("      ✓ Restored {}", path);
+                    }
+                    ManifestEntry::KernelArg { arg, .. } => {
+                        manifest.revert(entry)?;
+                        println!("      ✓ Removed kernel argument {}", arg);
+                    }
+                    ManifestEntry::EntryArg { path, arg } => {
+                        manifest.revert(entry)?;
+                        println!("      ✓ Removed kernel argument {} from {}", arg, path);
+                    }
+                    _ => {}
+                }
+            }
+
+            // Boot loader files were restored above; regenerate its config from them
+            if manifest.touches_boot_config() {
+                if let Some(loader) = BootLoader::detect() {
+                    loader.refresh().unwrap_or_else(|e| println!("      ⚠️  {}", e));
+                }
+            }
+        } else {
+            let status = Command::new("pkexec")
+            .args(["rm", "-f", "/etc/modprobe.d/vfio.conf", "/etc/modprobe.d/vfio-priority.conf"])
+            .status()
+            .map_err(|e| format!("Failed to remove VFIO config: {}", e))?;
+
+            if !status.success() {
+                return Err("Failed to remove VFIO config (authentication cancelled?)".to_string());
+            }
+
+            println!("      ✓ Removed /etc/modprobe.d/vfio.conf and vfio-priority.conf");
+        }
+
+        // Step 2: Remove lines added to shared files (initramfs modules, and the boot-time
+        // hugepages reservation older versions made)
+        println!("\n[2/5] 📝 Removing lines added to shared files...");
+        let reserved_at_boot = !Manifest::exists() || manifest.entries.iter().any(|e| matches!(
+            e,
+            ManifestEntry::Line { path, line } if path == "/etc/sysctl.conf" && line.starts_with("vm.nr_hugepages")
+        ));
+        if Manifest::exists() {
+            for entry in &manifest.entries {
+                if let ManifestEntry::Line { path, line } = entry {
+                    manifest.revert(entry).ok();
+                    println!("      ✓ Removed '{}' from {}", line, path);
+                }
+            }
+        } else {
+            Command::new("pkexec")
+            .args(["sed", "-i", r"/^vm\.nr_hugepages = [0-9]*$/d", "/etc/sysctl.conf"])
+            .status()
+            .ok();
+        }
+
+        // Only give back a reservation Ten Pod made; `start` frees its own pages when the VM exits
+        if reserved_at_boot {
+            Command::new("pkexec")
+            .args(["sysctl", "-w", "vm.nr_hugepages=0"])
+            .status()
+            .ok();
+            println!("      ✓ Hugepages reservation released");
+        }
+
+        // Step 3: Rebuild initramfs without vfio-pci claiming the GPU
+        println!("\n[3/5] 🔄 Rebuilding initramfs (may take 30-60 seconds)...");
+        match InitramfsTool::detect() {
+            Some(tool) => match tool.regenerate() {
+                Ok(()) => println!("      ✓ Initramfs rebuilt with {}", tool.name()),
+                Err(e) => println!("      ⚠️  {}", e),
+            },
+            None => println!("      ⚠️  No initramfs generator found, rebuild your initramfs by hand"),
+        }
+
+        // Step 4: Groups and packages
+        println!("\n[4/5] 👤 Reverting user permissions...");
+        for entry in &manifest.entries {
+            if let ManifestEntry::Group { user, group } = entry {
+                manifest.revert(entry).ok();
+                println!("      ✓ Removed '{}' from group {}", user, group);
+            }
+        }
+
+        let packages: Vec<&str> = manifest.entries.iter()
+        .filter_map(|e| match e {
+            ManifestEntry::Package { name } => Some(name.as_str()),
+            _ => None,
+        })
+        .collect();
+
+        if !packages.is_empty() {
+            println!("      Packages installed by Ten Pod (left in place):");
+            println!("        {}", packages.join(" "));
+        }
+
+        // Step 5: VM disk
+        println!("\n[5/5] 📀 VM disk image...");
+        let disk_path = manifest.entries.iter()
+        .find_map(|e| match e {
+            ManifestEntry::Disk { path } => Some(path.as_str()),
+            _ => None,
+        })
+        .unwrap_or(&self.config.disk_path);
+
+        if !remove_disk {
+            println!("      ✓ Kept {} (use --remove-disk to delete it)", disk_path);
+        } else if Path::new(disk_path).exists() {
+            let status = Command::new("pkexec")
+            .args(["rm", "-f", disk_path])
+            .status()
+            .map_err(|e| format!("Failed to remove disk: {}", e))?;
+
+            if !status.success() {
+                return Err(format!("Failed to remove {} (authentication cancelled?)", disk_path));
+            }
+
+            println!("      ✓ Deleted {}", disk_path);
+        } else {
+            println!("      ✓ No disk at {}", disk_path);
+        }
+
+        // Everything is reverted, the manifest and backups are no longer needed. The guest's
+        // UEFI variables and TPM (BitLocker and Windows Hello keys) stay with a kept disk.
+        let mut state = vec![MANIFEST_PATH.to_string(), BACKUP_DIR.to_string()];
+        if remove_disk {
+            state.push(vms_dir.clone());
+        }
+        Command::new("pkexec")
+        .arg("rm")
+        .arg("-rf")
+        .args(&state)
+        .status()
+        .ok();
+        if !remove_disk && Path::new(&vms_dir).exists() {
+            println!("      ✓ Kept UEFI variables and TPM state in {}", vms_dir);
+        } else if remove_disk {
+            Command::new("pkexec").args(["rmdir", "--ignore-fail-on-non-empty", STATE_DIR]).status().ok();
+        }
+
+        println!("\n✅ Uninstall Complete!\n");
+        println!("📋 Next Steps:");
+        println!("  1. REBOOT your system so the GPU binds to its Linux driver again");
+        println!("  2. NVIDIA only, optional: install the driver with: sudo dnf install akmod-nvidia");
+
+        Ok(())
+    }
+
+    /// NUMA node the GPU is attached to, None if the kernel doesn't know
+    fn gpu_numa_node(&self) -> Option<u32> {
+        fs::read_to_string(self.pci.devices_dir().join(&self.gpu_pci).join("numa_node")).ok()
+        .and_then(|n| n.trim().parse::<i32>().ok())
+        .filter(|&n| n >= 0)
+        .map(|n| n as u32)
+    }
+
+    /// NUMA node to keep the VM on: the GPU's with `vm.numa_node = auto`, so DMA doesn't cross
+    /// the socket interconnect. None on single-node hosts.
+    fn numa_node(&self) -> Result<Option<u32>, String> {
+        if self.cpus.nodes.len() < 2 {
+            return Ok(None);
+        }
+
+        match self.config.numa_node.as_str() {
+            "off" => Ok(None),
+            "auto" => Ok(self.gpu_numa_node()),
+            n => {
+                let node = n.parse::<u32>().map_err(|_| format!("Invalid vm.numa_node '{}'", n))?;
+                if !self.cpus.nodes.iter().any(|(id, _)| *id == node) {
+                    return Err(format!("vm.numa_node = {} but that NUMA node isn't online", node));
+                }
+                Ok(Some(node))
+            }
+        }
+    }
+
+    /// Decide which host CPU each vCPU runs on. With `cpu_cores = auto`, `vm.cores` whole cores
+    /// (SMT siblings included) are picked from one cache domain and the guest sees the same shape.
+    fn cpu_plan(&self) -> Result<CpuPlan, String> {
+        let node = self.numa_node()?;
+        let mut plan = if self.config.cpu_cores == "auto" {
+            let count = self.config.cores as usize;
+            let (cores, one_cache, node) = match self.cpus.select_cores(count, node) {
+                Ok((cores, one_cache)) => (cores, one_cache, node),
+                // Too few free cores next to the GPU: better unbound than not starting
+                Err(_) if node.is_some() && self.config.numa_node == "auto" => {
+                    let (cores, one_cache) = self.cpus.select_cores(count, None)?;
+                    (cores, one_cache, None)
+                }
+                Err(e) => return Err(e),
+            };
+            let threads = cores.iter().map(|c| c.cpus.len()).min().unwrap_or(1);
+            CpuPlan {
+                vcpus: cores.iter().flat_map(|c| c.cpus.iter().take(threads).copied()).collect(),
+                emulator: Vec::new(),
+                sockets: 1,
+                cores: cores.len() as u32,
+                threads: threads as u32,
+                one_cache,
+                node,
+            }
+        } else {
+            let host = parse_cpu_list(&self.config.cpu_cores)?;
+            let vcpus: Vec<u32> = (0..self.config.vcpus() as usize).map(|i| host[i % host.len()]).collect();
+            let l3 = |cpu: &u32| self.cpus.cores.iter().find(|c| c.cpus.contains(cpu)).map(|c| c.l3.clone());
+            let first = vcpus.first().and_then(l3);
+            CpuPlan {
+                one_cache: vcpus.iter().all(|c| l3(c) == first),
+                vcpus,
+                emulator: Vec::new(),
+                sockets: self.config.sockets,
+                cores: self.config.cores,
+                threads: self.config.threads,
+                node,
+            }
+        };
+
+        plan.emulator = if self.config.emulator_cores == "auto" {
+            let rest: Vec<u32> = self.cpus.online.iter().copied().filter(|c| !plan.vcpus.contains(c)).collect();
+            if rest.is_empty() { self.cpus.online.clone() } else { rest }
+        } else {
+            parse_cpu_list(&self.config.emulator_cores)?
+        };
+
+        Ok(plan)
+    }
+
+    /// Build the full QEMU invocation for this VM without touching the system
+    fn qemu_command(
+        &self,
+        headsets: &[(String, String, String)],
+        firmware: Option<&Ovmf>,
+        plan: &CpuPlan,
+        hugepages: Option<&HugePagePool>,
+    ) -> QemuCommand {
+        let mut qemu = QemuCommand::new("qemu-system-x86_64");
+
+        // QEMU and its helper threads start on the emulator CPUs; start() then moves each
+        // vCPU thread to its own host CPU while the guest is still paused (-S)
+        qemu.taskset = Some(format_cpu_list(&plan.emulator));
+        qemu.name = self.config.name.clone();
+        qemu.smp = plan.smp();
+        qemu.flag("-S");
+        qemu.memory_gb = self.config.memory_gb;
+
+        // Secure Boot needs SMM so the guest can't write the protected variables
+        qemu.machine = QemuOpts::new("q35")
+        .set("accel", "kvm")
+        .set("kernel_irqchip", "on");
+        if self.config.secure_boot {
+            qemu.machine = qemu.machine.set("smm", "on");
+        }
+
+        // CPU & Hyper-V Enlightenments for Latency Reduction
+        qemu.cpu = QemuOpts::new("host")
+        .flag("hv_time")
+        .flag("hv_relaxed")
+        .flag("hv_vapic")
+        .set("hv_spinlocks", "0x1fff")
+        .set("hv_vendor_id", "tenpodvr")
+        .set("kvm", "off")
+        .flag("+invtsc");
+
+        // UEFI firmware: read-only code plus this VM's own variable store
+        if let Some(firmware) = firmware {
+            qemu.drive(QemuOpts::props()
+                .set("if", "pflash")
+                .set("format", "raw")
+                .set("readonly", "on")
+                .set("file", &firmware.code));
+            qemu.drive(QemuOpts::props()
+                .set("if", "pflash")
+                .set("format", "raw")
+                .set("file", &self.nvram_path()));
+            if firmware.secure_boot {
+                qemu.option("-global", "driver=cfi.pflash01,property=secure,value=on");
+            }
+        }
+
+        // Software TPM 2.0: swtpm listens on a socket in the runtime dir
+        if self.config.tpm {
+            qemu.chardev(QemuOpts::new("socket")
+                .set("id", "chrtpm")
+                .set("path", &self.runtime_dir().join("swtpm.sock").display().to_string()));
+            qemu.tpmdev(QemuOpts::new("emulator").set("id", "tpm0").set("chardev", "chrtpm"));
+            qemu.device(QemuOpts::new("tpm-crb").set("tpmdev", "tpm0"));
+        }
+
+        // Timing Stability for smooth VR frame pacing
+        qemu.option("-rtc", "base=localtime,clock=host,driftfix=slew");
+        qemu.option("-global", "kvm-pit.lost_tick_policy=delay");
+        qemu.flag("-no-hpet");
+
+        // Guest RAM: hugepages for reduced memory latency, bound to the GPU's NUMA node
+        let size = format!("{}G", self.config.memory_gb);
+        let backend = match hugepages {
+            Some(pool) => Some(
+                QemuOpts::new("memory-backend-file")
+                .set("id", "mem0")
+                .set("size", &size)
+                .set("mem-path", pool.mount_point())
+                .set("prealloc", "on")
+            ),
+            None if plan.node.is_some() => Some(
+                QemuOpts::new("memory-backend-ram")
+                .set("id", "mem0")
+                .set("size", &size)
+            ),
+            None => None,
+        };
+        if let Some(mut backend) = backend {
+            if let Some(node) = plan.node {
+                backend = backend.set("host-nodes", &node.to_string()).set("policy", "bind");
+            }
+            qemu.object(backend);
+            qemu.machine = qemu.machine.set("memory-backend", "mem0");
+        }
+
+        // GPU Passthrough: all functions of the slot behind one root port, keeping their
+        // function numbers, so the guest sees the same multifunction card as the host
+        qemu.device(QemuOpts::new("pcie-root-port")
+            .set("id", "gpu-port")
+            .set("chassis", "1")
+            .set("slot", "1")
+            .set("bus", "pcie.0"));
+
+        for function in &self.gpu_functions {
+            let number = function.address.rsplit('.').next().unwrap_or("0");
+            let mut device = QemuOpts::new("vfio-pci")
+            .set("host", &function.address)
+            .set("bus", "gpu-port")
+            .set("addr", &format!("00.{}", number));
+            if number == "0" {
+                device = device.set("multifunction", "on");
+            }
+            qemu.device(device);
+        }
+
+        // Fast Disk I/O with virtio-scsi, on its own I/O thread
+        qemu.object(QemuOpts::new("iothread").set("id", "iothread0"));
+        qemu.device(QemuOpts::new("virtio-scsi-pci").set("id", "scsi0").set("iothread", "iothread0"));
+        qemu.drive(QemuOpts::props()
+            .set("file", &self.config.disk_path)
+            .set("format", "qcow2")
+            .set("if", "none")
+            .set("id", "dr1")
+            .set("cache", "none")
+            .set("aio", "native"));
+        qemu.device(QemuOpts::new("scsi-hd").set("drive", "dr1"));
+
+        // Boot from ISO if it exists (first-time Windows install)
+        if Path::new(&self.config.iso_path).exists() {
+            qemu.option("-cdrom", &self.config.iso_path);
+            qemu.option("-boot", "d");
+        }
+
+        // Enable USB 3.0 (Required for VR headsets)
+        qemu.device(QemuOpts::new("qemu-xhci").set("id", "usb-bus-0"));
+        qemu.flag("-usb");
+
+        // Auto-passthrough detected VR headsets
+        for (vendor, product, _) in headsets {
+            qemu.device(QemuOpts::new("usb-host")
+                .set("vendorid", &format!("0x{}", vendor))
+                .set("productid", &format!("0x{}", product)));
+        }
+
+        // Network (for Windows updates, SteamVR downloads)
+        qemu.netdev(QemuOpts::new("user").set("id", "net0"));
+        qemu.device(QemuOpts::new("virtio-net-pci").set("netdev", "net0"));
+
+        // QMP control socket, used by stop/status/pause/resume/reset
+        qemu.option("-qmp", &format!("unix:{},server=on,wait=off", self.qmp_path().display()));
+        qemu.option("-pidfile", &self.runtime_dir().join("qemu.pid").display().to_string());
+
+        // VGA Output: None (we use physical GPU output)
+        qemu.option("-vga", "none");
+        qemu.flag("-nographic");
+
+        // User-supplied extras from [qemu] extra_args
+        qemu.extra = self.config.extra_args.clone();
+
+        qemu
+    }
+
+    /// The same VM as a libvirt domain, for virt-manager and libvirt hooks
+    fn domain_xml(
+        &self,
+        headsets: &[(String, String, String)],
+        firmware: Option<&Ovmf>,
+        plan: &CpuPlan,
+        hugepages: Option<&HugePagePool>,
+    ) -> Result<String, String> {
+        let mut xml = String::new();
+
+        xml.push_str(&format!(
+            concat!(
+                "<domain type='kvm' xmlns:qemu='http://libvirt.org/schemas/domain/qemu/1.0'>\n",
+                "  <name>{}</name>\n",
+                "  <memory unit='GiB'>{}</memory>\n",
+                "  <vcpu placement='static'>{}</vcpu>\n",
+                "  <iothreads>1</iothreads>\n",
+            ),
+            xml_escape(&self.config.name),
+            self.config.memory_gb,
+            plan.vcpus.len()
+        ));
+
+        // Hugepages for reduced memory latency
+        if let Some(pool) = hugepages {
+            xml.push_str(&format!(
+                concat!(
+                    "  <memoryBacking>\n",
+                    "    <hugepages>\n",
+                    "      <page size='{}' unit='KiB'/>\n",
+                    "    </hugepages>\n",
+                    "  </memoryBacking>\n",
+                ),
+                pool.size_kb
+            ));
+        }
+
+        // Guest memory on the GPU's NUMA node
+        if let Some(node) = plan.node {
+            xml.push_str(&format!("  <numatune>\n    <memory mode='strict' nodeset='{}'/>\n  </numatune>\n", node));
+        }
+
+        // CPU pinning: one host CPU per vCPU, emulator and I/O thread kept off them
+        xml.push_str("  <cputune>\n");
+        for (vcpu, cpu) in plan.vcpus.iter().enumerate() {
+            xml.push_str(&format!("    <vcpupin vcpu='{}' cpuset='{}'/>\n", vcpu, cpu));
+        }
+        let emulator = format_cpu_list(&plan.emulator);
+        xml.push_str(&format!("    <emulatorpin cpuset='{}'/>\n", emulator));
+        xml.push_str(&format!("    <iothreadpin iothread='1' cpuset='{}'/>\n  </cputune>\n", emulator));
+
+        // Firmware: libvirt keeps its own copy of the variable store, made from the template
+        xml.push_str("  <os>\n    <type arch='x86_64' machine='q35'>hvm</type>\n");
+        if let Some(firmware) = firmware {
+            xml.push_str(&format!(
+                "    <loader readonly='yes' secure='{}' type='pflash'>{}</loader>\n    <nvram template='{}'/>\n",
+                if firmware.secure_boot { "yes" } else { "no" },
+                xml_escape(&firmware.code),
+                xml_escape(&firmware.vars)
+            ));
+        }
+        if Path::new(&self.config.iso_path).exists() {
+            xml.push_str("    <boot dev='cdrom'/>\n");
+        }
+        xml.push_str("    <boot dev='hd'/>\n  </os>\n");
+
+        // Hyper-V enlightenments, matching the -cpu flags of the QEMU backend
+        xml.push_str(concat!(
+            "  <features>\n    <acpi/>\n    <apic/>\n    <hyperv mode='custom'>\n",
+            "      <relaxed state='on'/>\n      <vapic state='on'/>\n      <spinlocks state='on' retries='8191'/>\n",
+            "      <vendor_id state='on' value='tenpodvr'/>\n    </hyperv>\n    <kvm>\n      <hidden state='on'/>\n    </kvm>\n",
+        ));
+        if self.config.secure_boot {
+            xml.push_str("    <smm state='on'/>\n");
+        }
+        xml.push_str("  </features>\n");
+
+        xml.push_str(&format!(
+            concat!(
+                "  <cpu mode='host-passthrough' check='none'>\n",
+                "    <topology sockets='{}' dies='1' cores='{}' threads='{}'/>\n",
+                "    <feature policy='require' name='invtsc'/>\n  </cpu>\n",
+            ),
+            plan.sockets, plan.cores, plan.threads
+        ));
+
+        // Timing Stability for smooth VR frame pacing
+        xml.push_str(concat!(
+            "  <clock offset='localtime'>\n    <timer name='rtc' tickpolicy='catchup' track='wall'/>\n",
+            "    <timer name='pit' tickpolicy='delay'/>\n    <timer name='hpet' present='no'/>\n",
+            "    <timer name='hypervclock' present='yes'/>\n  </clock>\n",
+        ));
+
+        xml.push_str("  <devices>\n    <emulator>/usr/bin/qemu-system-x86_64</emulator>\n");
+
+        // Fast Disk I/O with virtio-scsi
+        xml.push_str(&format!(
+            concat!(
+                "    <controller type='scsi' index='0' model='virtio-scsi'>\n      <driver iothread='1'/>\n    </controller>\n",
+                "    <disk type='file' device='disk'>\n      <driver name='qemu' type='qcow2' cache='none' io='native'/>\n",
+                "      <source file='{}'/>\n      <target dev='sda' bus='scsi'/>\n    </disk>\n",
+            ),
+            xml_escape(&self.config.disk_path)
+        ));
+
+        if Path::new(&self.config.iso_path).exists() {
+            xml.push_str(&format!(
+                concat!(
+                    "    <disk type='file' device='cdrom'>\n      <driver name='qemu' type='raw'/>\n",
+                    "      <source file='{}'/>\n      <target dev='sdb' bus='sata'/>\n      <readonly/>\n    </disk>\n",
+                ),
+                xml_escape(&self.config.iso_path)
+            ));
+        }
+
+        // GPU Passthrough: every function of the slot on one root port, same function numbers.
+        // managed='yes' lets libvirt move the GPU to vfio-pci and back around each run.
+        xml.push_str("    <controller type='pci' index='1' model='pcie-root-port'/>\n");
+        for function in &self.gpu_functions {
+            let (domain, rest) = function.address.split_once(':').unwrap_or(("0000", &function.address));
+            let (bus, rest) = rest.split_once(':').unwrap_or(("00", rest));
+            let (slot, number) = rest.split_once('.').unwrap_or((rest, "0"));
+            xml.push_str(&format!(
+                concat!(
+                    "    <hostdev mode='subsystem' type='pci' managed='yes'>\n",
+                    "      <source>\n        <address domain='0x{}' bus='0x{}' slot='0x{}' function='0x{}'/>\n      </source>\n",
+                    "      <address type='pci' domain='0x0000' bus='0x01' slot='0x00' function='0x{}'{}/>\n    </hostdev>\n",
+                ),
+                domain, bus, slot, number, number,
+                if number == "0" { " multifunction='on'" } else { "" }
+            ));
+        }
+
+        // USB 3.0 and the detected VR headsets
+        xml.push_str("    <controller type='usb' index='0' model='qemu-xhci'/>\n");
+        for (vendor, product, _) in headsets {
+            xml.push_str(&format!(
+                concat!(
+                    "    <hostdev mode='subsystem' type='usb' managed='yes'>\n",
+                    "      <source startupPolicy='optional'>\n        <vendor id='0x{}'/>\n        <product id='0x{}'/>\n      </source>\n    </hostdev>\n",
+                ),
+                vendor, product
+            ));
+        }
+
+        // Software TPM 2.0: libvirt runs swtpm itself
+        if self.config.tpm {
+            xml.push_str("    <tpm model='tpm-crb'>\n      <backend type='emulator' version='2.0'/>\n    </tpm>\n");
+        }
+
+        // Network (for Windows updates, SteamVR downloads)
+        xml.push_str(
+            "    <interface type='network'>\n      <source network='default'/>\n      <model type='virtio'/>\n    </interface>\n",
+        );
+
+        // VGA Output: None (we use physical GPU output)
+        xml.push_str("    <video>\n      <model type='none'/>\n    </video>\n");
+        xml.push_str("    <memballoon model='none'/>\n  </devices>\n");
+
+        // User-supplied extras from [qemu] extra_args
+        if !self.config.extra_args.is_empty() {
+            xml.push_str("  <qemu:commandline>\n");
+            for arg in &self.config.extra_args {
+                xml.push_str(&format!("    <qemu:arg value='{}'/>\n", xml_escape(arg)));
+            }
+            xml.push_str("  </qemu:commandline>\n");
+        }
+
+        xml.push_str("</domain>\n");
+        Ok(xml)
+    }
+
+    /// Write the libvirt domain XML for this VM, ready for `virsh define`
+    fn export_xml(&self, headsets: &[(String, String, String)], output: &str) -> Result<(), String> {
+        let firmware = if self.firmware_type() == "uefi" {
+            Some(self.find_ovmf()?)
+        } else {
+            None
+        };
+
+        let plan = self.cpu_plan()?;
+        let xml = self.domain_xml(headsets, firmware.as_ref(), &plan, self.hugepage_plan(&plan).as_ref())?;
+        if output == "-" {
+            print!("{}", xml);
+            return Ok(());
+        }
+
+        fs::write(output, xml).map_err(|e| format!("Failed to write {}: {}", output, e))?;
+        println!("\n✓ Wrote libvirt domain to {}", output);
+        println!("  Import it with: virsh -c {} define {}", LIBVIRT_URI, output);
+        Ok(())
+    }
+
+    /// Define the domain in libvirt from the current config and start it
+    fn start_libvirt(&self, headsets: Vec<(String, String, String)>) -> Result<(), String> {
+        println!("\n🚀 Launching Ten Pod VM through libvirt...\n");
+
+        if !Path::new(&self.config.disk_path).exists() {
+            return Err(format!("VM disk not found at {}\nRun: ./tenpod.rs install", self.config.disk_path));
+        }
+
+        let firmware = if self.firmware_type() == "uefi" {
+            Some(self.find_ovmf()?)
+        } else {
+            None
+        };
+
+        for (_, _, name) in &headsets {
+            println!("🎧 Passing through: {}", name);
+        }
+
+        // Redefine every time so config changes and newly plugged headsets are picked up
+        let runtime = self.runtime_dir();
+        fs::create_dir_all(&runtime)
+        .map_err(|e| format!("Failed to create {}: {}", runtime.display(), e))?;
+        let xml_path = runtime.join("domain.xml");
+        let plan = self.cpu_plan()?;
+
+        let hugepages = self.reserve_hugepages(&plan)?;
+        let release = |e: String| {
+            if let Some(pool) = &hugepages {
+                self.release_hugepages(pool);
+            }
+            e
+        };
+
+        let xml = self.domain_xml(&headsets, firmware.as_ref(), &plan, hugepages.as_ref()).map_err(release)?;
+        fs::write(&xml_path, xml)
+        .map_err(|e| release(format!("Failed to write {}: {}", xml_path.display(), e)))?;
+
+        virsh(&["define", &xml_path.to_string_lossy()]).map_err(release)?;
+        println!("✓ Defined libvirt domain '{}' ({})", self.config.name, LIBVIRT_URI);
+
+        virsh(&["start", &self.config.name]).map_err(release)?;
+        println!("✓ VM started, it is also visible in virt-manager");
+        println!("📺 Check your GPU's physical monitor output for Windows display");
+
+        // libvirt doesn't give the pages back, so stay around until the domain is shut off
+        if let Some(pool) = hugepages.as_ref().filter(|p| p.added > 0 || p.mounted) {
+            println!("\n💾 Waiting for the VM to shut down to release its hugepages (Ctrl+C leaves them allocated)");
+            self.wait_for_libvirt_shutdown();
+            self.release_hugepages(pool);
+        }
+        Ok(())
+    }
+
+    /// Block until the libvirt domain is shut off (or gone)
+    fn wait_for_libvirt_shutdown(&self) {
+        loop {
+            match virsh(&["domstate", &self.config.name]) {
+                Ok(state) if !matches!(state.trim(), "shut off" | "crashed") => {
+                    std::thread::sleep(Duration::from_secs(2));
+                }
+                _ => return,
+            }
+        }
+    }
+
+    /// Print the QEMU command `start` would run, shell-quoted, without starting anything
+    fn print_start_command(&self, headsets: &[(String, String, String)]) -> Result<(), String> {
+        let firmware = if self.firmware_type() == "uefi" {
+            Some(self.find_ovmf()?)
+        } else {
+            None
+        };
+
+        if self.config.tpm {
+            println!("# swtpm socket --tpm2 --daemon --terminate --tpmstate dir={}/tpm --ctrl type=unixio,path={}",
+                self.vm_state_dir(),
+                self.runtime_dir().join("swtpm.sock").display());
+        }
+        let plan = self.cpu_plan()?;
+        if let Some(pool) = self.hugepage_plan(&plan) {
+            println!("# allocate {} for guest memory, freed on exit", pool.describe());
+        }
+        if self.config.isolate_host {
+            println!("# host slices ({}) limited to AllowedCPUs={} for the session",
+                ISOLATED_UNITS.join(", "),
+                format_cpu_list(&plan.emulator));
+        }
+        // Nothing sends a QMP cont to a copied command, so it has to boot without -S
+        let mut qemu = self.qemu_command(headsets, firmware.as_ref(), &plan, self.hugepage_plan(&plan).as_ref());
+        qemu.options.retain(|(name, _)| name != "-S");
+        println!("# vCPU threads are not pinned individually when run by hand");
+        println!("{}", qemu.shell_line());
+        Ok(())
+    }
+
+    /// Start the VM with VR optimizations
+    fn start(&self, headsets: Vec<(String, String, String)>) -> Result<(), String> {
+        println!("\n🚀 Launching Ten Pod VM (VR Optimized)...\n");
+
+        let disk_path = self.config.disk_path.as_str();
+
+        // Pre-flight checks
+        if !Path::new(disk_path).exists() {
+            return Err(format!("VM disk not found at {}\nRun: ./tenpod.rs install", disk_path));
+        }
+
+        let firmware = if self.firmware_type() == "uefi" {
+            let (firmware, _) = self.prepare_nvram()?;
+            println!("🧬 UEFI firmware: {}{}", firmware.code, if firmware.secure_boot { " (Secure Boot)" } else { "" });
+            Some(firmware)
+        } else {
+            if self.config.firmware == "auto" {
+                println!("🧬 Legacy BIOS: this disk was installed before UEFI became the default (set firmware.type to override)");
+            }
+            None
+        };
+
+        // AMD reset bug: let vendor-reset handle the GPU reset between VM boots
+        if self.needs_vendor_reset {
+            if !self.config.vendor_reset {
+                println!("⚠️  AMD reset bug: if the VM fails to start a second time, reboot the host");
+            } else if !Path::new("/sys/module/vendor_reset").exists() {
+                println!("⚠️  vendor_reset is enabled but the vendor-reset module isn't loaded");
+            } else {
+                let reset_method = self.pci.devices_dir().join(&self.gpu_pci).join("reset_method");
+                if reset_method.exists() {
+                    Command::new("pkexec")
+                    .arg("sh")
+                    .arg("-c")
+                    .arg(format!("echo device_specific > {}", reset_method.display()))
+                    .status()
+                    .ok();
+                }
+                println!("🔁 Using vendor-reset for AMD GPU reset");
+            }
+        }
+
+        println!("🎮 Passing through GPU: {}", self.gpu_pci);
+        for function in &self.gpu_functions {
+            if function.address != self.gpu_pci {
+                println!("🔊 Passing through GPU {}: {}", function.role(), function.address);
+            }
+        }
+
+        if Path::new(&self.config.iso_path).exists() {
+            println!("📀 Windows ISO detected, booting from ISO for installation");
+        }
+
+        if !headsets.is_empty() {
+            for (_, _, name) in &headsets {
+                println!("🎧 Passing through: {}", name);
+            }
+        } else {
+            println!("⚠️  No VR headset detected - plug it in and restart VM");
+        }
+
+        let runtime = self.runtime_dir();
+        fs::create_dir_all(&runtime)
+        .map_err(|e| format!("Failed to create {}: {}", runtime.display(), e))?;
+
+        if let Some(pid) = self.qemu_pid() {
+            return Err(format!("VM '{}' is already running as pid {} (./tenpod.rs stop first)", self.config.name, pid));
+        }
+        fs::remove_file(self.qmp_path()).ok();
+
+        let plan = self.cpu_plan()?;
+        println!(
+            "🧮 {} vCPUs ({} cores x {} threads) on host CPUs {}, emulator and I/O on {}",
+            plan.vcpus.len(), plan.sockets * plan.cores, plan.threads,
+            format_cpu_list(&plan.vcpus), format_cpu_list(&plan.emulator)
+        );
+        if !plan.one_cache {
+            println!("⚠️  The vCPUs span more than one L3 cache; expect less consistent frame times");
+        }
+        if let Some(node) = plan.node {
+            println!("🧭 vCPUs and guest memory bound to NUMA node {}", node);
+        } else if self.config.numa_node == "auto" && self.gpu_numa_node().is_some() && self.cpus.nodes.len() > 1 {
+            println!("⚠️  Not enough free cores on the GPU's NUMA node, the VM is not bound to a node");
+        }
+
+        let hugepages = self.reserve_hugepages(&plan)?;
+
+        // Give the pages back if anything fails before QEMU runs
+        let release = |e: String| {
+            if let Some(pool) = &hugepages {
+                self.release_hugepages(pool);
+            }
+            e
+        };
+
+        let qemu = self.qemu_command(&headsets, firmware.as_ref(), &plan, hugepages.as_ref());
+        let mut cmd = if self.config.isolate_host {
+            println!("🧱 Host tasks confined to CPUs {} while the VM runs", format_cpu_list(&plan.emulator));
+            self.isolated_command(&qemu.argv(), &plan).map_err(release)?
+        } else {
+            qemu.command()
+        };
+
+        // swtpm exits on its own once QEMU closes the connection
+        if self.config.tpm {
+            let socket = self.start_tpm().map_err(release)?;
+            println!("🔐 TPM 2.0 (swtpm): {}", socket.display());
+        }
+
+        println!("\n💻 VM Starting...");
+        println!("📺 Check your GPU's physical monitor output for Windows display");
+        println!("🎮 Once Windows boots, install {} drivers and SteamVR", self.gpu_vendor.name());
+        println!("   Get them from: {}\n", self.gpu_vendor.driver_url());
+
+        // Hand the GPU to vfio-pci for this session only
+        let rebind = self.config.auto_bind && !self.gpu_on_vfio();
+        if rebind {
+            self.bind_vfio().map_err(release)?;
+        }
+
+        let result = cmd.spawn()
+        .map_err(|e| format!("Failed to start VM: {}", e))
+        .and_then(|mut child| {
+            if let Err(e) = self.pin_and_resume(&plan, &mut child) {
+                // Under isolation the child is a root shell; QEMU itself is found via its pidfile
+                if let Some(pid) = self.qemu_pid() {
+                    Command::new("kill").arg(pid.to_string()).status().ok();
+                }
+                child.kill().ok();
+                child.wait().ok();
+                return Err(e);
+            }
+            child.wait().map_err(|e| format!("Failed to wait for QEMU: {}", e))
+        });
+
+        if let Ok(status) = &result {
+            println!("\n🛑 QEMU exited ({})", status);
+        }
+
+        if self.config.tpm {
+            self.stop_tpm();
+        }
+        fs::remove_file(self.qmp_path()).ok();
+        fs::remove_file(runtime.join("qemu.pid")).ok();
+
+        if let Some(pool) = &hugepages {
+            self.release_hugepages(pool);
+        }
+
+        if rebind {
+            println!("\n🔁 VM exited, returning GPU to the host...");
+            if let Err(e) = self.unbind_vfio() {
+                println!("⚠️  {}", e);
+            }
+        }
+
+        // A non-zero exit means QEMU failed or crashed (lets systemd's Restart=on-failure act)
+        let status = result?;
+        if !status.success() {
+            return Err(format!("QEMU exited with {}", status));
+        }
+        Ok(())
+    }
+
+    /// Pin each vCPU thread to its host CPU and the I/O thread to the emulator CPUs, then let
+    /// the paused guest run
+    fn pin_and_resume(&self, plan: &CpuPlan, child: &mut std::process::Child) -> Result<(), String> {
+        let deadline = Instant::now() + Duration::from_secs(30);
+        let mut qmp = loop {
+            if let Ok(Some(status)) = child.try_wait() {
+                return Err(format!("QEMU exited during startup ({})", status));
+            }
+            if let Ok(qmp) = Qmp::connect(&self.qmp_path()) {
+                break qmp;
+            }
+            if Instant::now() >= deadline {
+                return Err("QEMU's QMP socket did not come up".to_string());
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        };
+
+        // A pinning failure costs latency, not correctness: warn and boot anyway
+        match qmp.vcpu_threads() {
+            Ok(threads) => {
+                let mut pinned = 0;
+                for (vcpu, tid) in threads.iter().enumerate() {
+                    let cpu = plan.vcpus[vcpu % plan.vcpus.len()];
+                    match pin_thread(*tid, &[cpu]) {
+                        Ok(()) => pinned += 1,
+                        Err(e) => println!("⚠️  vCPU {}: {}", vcpu, e),
+                    }
+                }
+                println!("📌 Pinned {} of {} vCPU threads", pinned, threads.len());
+            }
+            Err(e) => println!("⚠️  Could not pin vCPU threads: {}", e),
+        }
+
+        if let Ok(threads) = qmp.iothread_threads() {
+            for tid in threads {
+                pin_thread(tid, &plan.emulator).ok();
+            }
+        }
+
+        qmp.execute("cont", None)?;
+        Ok(())
+    }
+
+    /// The hugepages a VM of this size needs, without allocating anything
+    fn hugepage_plan(&self, plan: &CpuPlan) -> Option<HugePagePool> {
+        let size_kb = self.config.hugepage_kb();
+        if self.config.hugepages == "off" || !self.hugepages.supported(size_kb) {
+            return None;
+        }
+
+        Some(HugePagePool {
+            size_kb,
+            // The bound node, otherwise the node the vCPUs run on
+            node: plan.node.or_else(|| plan.vcpus.first().and_then(|&cpu| self.cpus.node_of(cpu))),
+            pages: self.config.memory_gb as u64 * 1024 * 1024 / size_kb,
+            added: 0,
+            mounted: false,
+        })
+    }
+
+    /// Make sure enough free hugepages exist for the guest, compacting memory and growing the
+    /// pool if needed. Falls back to normal memory unless `hugepages.mode = required`.
+    fn reserve_hugepages(&self, plan: &CpuPlan) -> Result<Option<HugePagePool>, String> {
+        if self.config.hugepages == "off" {
+            return Ok(None);
+        }
+
+        let mut pool = match self.hugepage_plan(plan) {
+            Some(pool) => pool,
+            None => {
+                let unsupported = format!("This host doesn't support {} hugepages", self.config.hugepage_size);
+                if self.config.hugepages == "required" {
+                    return Err(format!("{} (hugepages.mode = required)", unsupported));
+                }
+                println!("⚠️  {}, using normal memory", unsupported);
+                return Ok(None);
+            }
+        };
+
+        let mut steps = Vec::new();
+        let mount = pool.mount_point();
+        let was_mounted = hugetlbfs_mounted(mount);
+        if !was_mounted {
+            steps.push(format!(
+                "mkdir -p {0} && mount -t hugetlbfs -o pagesize={1}K none {0}",
+                mount, pool.size_kb
+            ));
+        }
+
+        let free = self.hugepages.read(&pool, "free_hugepages");
+        let total = self.hugepages.read(&pool, "nr_hugepages");
+        if free < pool.pages {
+            println!("💾 Allocating {} (compacting memory first)...", pool.describe());
+
+            // Dropping caches and compacting gives the kernel contiguous memory to carve pages from
+            steps.push("sync; echo 3 > /proc/sys/vm/drop_caches; echo 1 > /proc/sys/vm/compact_memory".to_string());
+            steps.push(format!(
+                "echo {} > {}",
+                total + pool.pages - free,
+                self.hugepages.pool_dir(&pool).join("nr_hugepages").display()
+            ));
+        }
+
+        if !steps.is_empty() {
+            let status = Command::new("pkexec")
+            .arg("sh")
+            .arg("-c")
+            .arg(steps.join("; "))
+            .status()
+            .map_err(|e| format!("Failed to allocate hugepages: {}", e))?;
+            if !status.success() {
+                println!("⚠️  Could not allocate hugepages: {}", pkexec_failure(status));
+            }
+
+            pool.added = self.hugepages.read(&pool, "nr_hugepages").saturating_sub(total);
+            pool.mounted = !was_mounted && hugetlbfs_mounted(mount);
+        }
+
+        // The pool's free count (HugePages_Free) is what QEMU can actually get
+        let free = self.hugepages.read(&pool, "free_hugepages");
+        if free >= pool.pages {
+            println!("💾 Guest memory on {}", pool.describe());
+            return Ok(Some(pool));
+        }
+
+        self.release_hugepages(&pool);
+        let shortage = format!(
+            "Only {} of {} hugepages are free; host memory is too fragmented or too full",
+            free, pool.pages
+        );
+        if self.config.hugepages == "required" {
+            // 1GB pages rarely survive fragmentation after boot
+            let hint = if pool.size_kb == HUGEPAGE_1G_KB {
+                format!(
+                    "Reserve them at boot with hugepagesz=1G hugepages={} on the kernel command line, or set hugepages.size = 2M",
+                    pool.pages
+                )
+            } else {
+                "Close some applications and retry, or set hugepages.mode = auto to fall back to normal memory".to_string()
+            };
+            return Err(format!("{}\n{}", shortage, hint));
+        }
+        println!("⚠️  {}, using normal memory", shortage);
+        Ok(None)
+    }
+
+    /// Give back the pages `reserve_hugepages` added to the pool, and unmount the hugetlbfs
+    /// if it mounted one
+    fn release_hugepages(&self, pool: &HugePagePool) {
+        let mut steps = Vec::new();
+        if pool.mounted {
+            steps.push(format!("umount {0} && rmdir {0}", pool.mount_point()));
+        }
+        if pool.added > 0 {
+            let total = self.hugepages.read(pool, "nr_hugepages");
+            steps.push(format!(
+                "echo {} > {}",
+                total.saturating_sub(pool.added),
+                self.hugepages.pool_dir(pool).join("nr_hugepages").display()
+            ));
+        }
+        if steps.is_empty() {
+            return;
+        }
+
+        let released = Command::new("pkexec")
+        .arg("sh")
+        .arg("-c")
+        .arg(steps.join("; "))
+        .status();
+
+        match released {
+            Ok(status) if status.success() && pool.added == 0 => println!("💾 Unmounted {}", pool.mount_point()),
+            Ok(status) if status.success() => println!("💾 Released {} hugepages", pool.added),
+            Ok(status) => println!("⚠️  Could not release {} hugepages: {}", pool.added, pkexec_failure(status)),
+            Err(e) => println!("⚠️  Could not release {} hugepages: {}", pool.added, e),
+        }
+    }
+
+    /// Show the host's cores and cache domains and which CPUs the VM would get
+    fn topology(&self) -> Result<(), String> {
+        println!("\n🧮 HOST CPU TOPOLOGY\n");
+        let plan = self.cpu_plan();
+
+        for (i, domain) in self.cpus.cache_domains().iter().enumerate() {
+            let cpus: Vec<u32> = domain.iter().flat_map(|c| c.cpus.iter().copied()).collect();
+            println!("L3 domain {} (package {}): CPUs {}", i, domain[0].package, format_cpu_list(&cpus));
+
+            for core in domain {
+                let role = match &plan {
+                    Ok(plan) if core.cpus.iter().any(|c| plan.vcpus.contains(c)) => "→ VM",
+                    _ if core.cpus.contains(&0) => "host (CPU 0)",
+                    _ => "host",
+                };
+                println!(
+                    "  core {:<10} {}{}",
+                    format_cpu_list(&core.cpus),
+                    if core.efficiency { "E-core  " } else { "" },
+                    role
+                );
+            }
+        }
+
+        let plan = plan?;
+        println!("\nGuest: -smp {}", plan.smp());
+        println!("vCPU → host CPU: {:?}", plan.vcpus);
+        println!("Emulator and I/O threads: {}", format_cpu_list(&plan.emulator));
+        if !plan.one_cache {
+            println!("⚠️  The vCPUs span more than one L3 cache");
+        }
+        if self.cpus.nodes.len() > 1 {
+            for (node, cpus) in &self.cpus.nodes {
+                println!("NUMA node {}: CPUs {}", node, format_cpu_list(cpus));
+            }
+            match self.gpu_numa_node() {
+                Some(node) => println!("GPU {} is on NUMA node {}", self.gpu_pci, node),
+                None => println!("GPU {} has no NUMA node information", self.gpu_pci),
+            }
+            match plan.node {
+                Some(node) => println!("vCPUs and guest memory: NUMA node {}", node),
+                None => println!("vCPUs and guest memory: not bound to a node"),
+            }
+        }
+        if self.config.isolate_host {
+            println!("Host tasks while the VM runs: {}", format_cpu_list(&plan.emulator));
+        }
+        Ok(())
+    }
+
+    /// Wrap QEMU in one root shell that confines the host slices to the emulator CPUs, runs
+    /// QEMU as this user in its own scope and restores the slices when it exits. The restore
+    /// runs in the root shell, so it still happens if tenpod itself is killed.
+    fn isolated_command(&self, argv: &[String], plan: &CpuPlan) -> Result<Command, String> {
+        use std::os::unix::fs::MetadataExt;
+
+        let me = fs::metadata("/proc/self")
+        .map_err(|e| format!("Failed to read /proc/self: {}", e))?;
+
+        let script = format!(
+            concat!(
+                "trap : INT TERM HUP\n",
+                "for unit in {units}; do systemctl set-property --runtime -- \"$unit\" AllowedCPUs={host}; done\n",
+                "systemd-run --scope --quiet --collect --slice=machine.slice --unit=tenpod-{name} --uid={uid} --gid={gid} -- \"$@\"\n",
+                "rc=$?\n",
+                "for unit in {units}; do systemctl set-property --runtime -- \"$unit\" AllowedCPUs=; done\n",
+                "exit $rc\n"
+            ),
+            units = ISOLATED_UNITS.join(" "),
+            host = format_cpu_list(&plan.emulator),
+            name = self.config.name,
+            uid = me.uid(),
+            gid = me.gid()
+        );
+
+        let mut cmd = Command::new("pkexec");
+        cmd.arg("sh")
+        .arg("-c")
+        .arg(script)
+        .arg("sh")
+        .args(argv);
+        Ok(cmd)
+    }
+
+    /// Name of this VM's systemd unit
+    fn unit_name(&self) -> String {
+        format!("tenpod-{}.service", self.config.name)
+    }
+
+    /// Where the unit lives: the user's systemd directory, or /etc/systemd/system
+    fn unit_path(&self, system: bool) -> Result<String, String> {
+        if system {
+            return Ok(format!("{}/{}", SYSTEM_UNIT_DIR, self.unit_name()));
+        }
+
+        let config_home = std::env::var("XDG_CONFIG_HOME").ok()
+        .filter(|d| !d.is_empty())
+        .or_else(|| std::env::var("HOME").ok().map(|h| format!("{}/.config", h)))
+        .ok_or("Cannot find your config directory ($HOME is not set)")?;
+        Ok(format!("{}/systemd/user/{}", config_home, self.unit_name()))
+    }
+
+    /// A unit that runs `tenpod start` in the foreground and `tenpod stop` to shut down. Both get
+    /// the config options `service install` was given; `start_flags` (e.g. --force) go to start only.
+    fn unit_file(&self, system: bool, start_flags: &[String]) -> Result<String, String> {
+        let join = |options: &[String]| -> String {
+            options.iter().map(|o| format!(" {}", systemd_quote(o))).collect()
+        };
+        let exe = join(&self_command()?).trim_start().to_string();
+        let stop_options = join(&self.config.cli_options);
+        let start_options = format!("{}{}", stop_options, join(start_flags));
+
+        // A system unit runs as this user with the same runtime directory as the user's own shell,
+        // so status/stop/pause find its QMP socket and pidfile. user-runtime-dir@ creates
+        // /run/user/<uid> even when nobody is logged in.
+        let uid = if system {
+            use std::os::unix::fs::MetadataExt;
+            let me = fs::metadata("/proc/self")
+            .map_err(|e| format!("Failed to read /proc/self: {}", e))?;
+            Some(me.uid())
+        } else {
+            None
+        };
+
+        let mut unit = format!(
+            "# Generated by tenpod service install\n\
+[Unit]\n\
+Description=Ten Pod VM ({name})\n\
+After=network-online.target{after}\n",
+            name = self.config.name,
+            after = match uid {
+                Some(uid) => format!(" libvirtd.service user-runtime-dir@{}.service", uid),
+                None => String::new(),
+            }
+        );
+        if let Some(uid) = uid {
+            unit.push_str(&format!("Requires=user-runtime-dir@{}.service\n", uid));
+        }
+        unit.push_str("\n[Service]\nType=simple\n");
+
+        if let Some(uid) = uid {
+            let user = std::env::var("USER").unwrap_or_else(|_| "root".to_string());
+            unit.push_str(&format!("User={}\nEnvironment=XDG_RUNTIME_DIR=/run/user/{}\n", user, uid));
+        }
+
+        // stop waits shutdown_timeout, then up to 10s each for SIGTERM and SIGKILL
+        unit.push_str(&format!(
+            "ExecStart={exe} start{start_options}\n\
+ExecStop={exe} stop{stop_options}\n\
+TimeoutStopSec={timeout}\n\
+KillMode=mixed\n\
+Restart=on-failure\n\
+RestartSec=10\n\n\
+[Install]\n\
+WantedBy={target}\n",
+            exe = exe,
+            start_options = start_options,
+            stop_options = stop_options,
+            timeout = self.config.shutdown_timeout + 30,
+            target = if system { "multi-user.target" } else { "default.target" }
+        ));
+
+        Ok(unit)
+    }
+
+    /// Settings that make every `start` ask for a password (through pkexec)
+    fn start_prompts(&self) -> Vec<&'static str> {
+        let mut prompts = Vec::new();
+        if self.config.isolate_host {
+            prompts.push("isolation.runtime = true (isolation.kernel_args isolates the cores without a prompt)");
+        }
+        if self.config.auto_bind {
+            prompts.push("gpu.auto_bind = true (let install bind the GPU to vfio-pci at boot instead)");
+        }
+        if self.config.hugepages == "required" {
+            prompts.push("hugepages.mode = required (use auto, or reserve the pages at boot)");
+        }
+        prompts
+    }
+
+    /// `service install|uninstall [--system]`: manage the VM with systemctl
+    fn service(&self, action: &str, system: bool, start_flags: &[String]) -> Result<(), String> {
+        let path = self.unit_path(system)?;
+        let unit = self.unit_name();
+        let systemctl = |args: &[&str]| -> bool {
+            let mut cmd = if system { Command::new("pkexec") } else { Command::new("systemctl") };
+            if system {
+                cmd.arg("systemctl");
+            } else {
+                cmd.arg("--user");
+            }
+            cmd.args(args).status().map(|s| s.success()).unwrap_or(false)
+        };
+
+        match action {
+            "install" => {
+                // A service has no polkit agent, so nobody could answer these prompts and the
+                // unit would fail and restart forever
+                let prompts = self.start_prompts();
+                if !prompts.is_empty() {
+                    return Err(format!(
+                        "A service can't ask for your password, which these settings need at every start:\n  {}\n\
+Change them in your config, or pass e.g. --set gpu.auto_bind=false to service install.",
+                        prompts.join("\n  ")
+                    ));
+                }
+
+                let contents = self.unit_file(system, start_flags)?;
+                if self.dry_run {
+                    println!("# {}", path);
+                    print!("{}", contents);
+                    return Ok(());
+                }
+
+                // The TPM state directory is created as root once; do it now, while someone can answer
+                if self.config.tpm {
+                    self.prepare_tpm_state()?;
+                }
+
+                if system {
+                    let mut manifest = Manifest::load();
+                    manifest.write_file(&path, &contents)?;
+                    manifest.save()?;
+                } else {
+                    if let Some(dir) = Path::new(&path).parent() {
+                        fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+                    }
+                    fs::write(&path, &contents).map_err(|e| format!("Failed to write {}: {}", path, e))?;
+                }
+
+                systemctl(&["daemon-reload"]);
+                println!("✓ Wrote {}", path);
+
+                let scope = if system { "" } else { "--user " };
+                println!("\n  Start now:       systemctl {}start {}", scope, unit);
+                println!("  Start at boot:   systemctl {}enable {}", scope, unit);
+                println!("  Logs:            journalctl {}-u {} -f", if system { "" } else { "--user " }, unit);
+                if !system {
+                    println!("  (to start before you log in: loginctl enable-linger)");
+                }
+                Ok(())
+            }
+
+            "uninstall" => {
+                if !Path::new(&path).exists() {
+                    return Err(format!("No unit at {}", path));
+                }
+
+                systemctl(&["disable", "--now", &unit]);
+
+                if system {
+                    let mut manifest = Manifest::load();
+                    if let Some(entry) = manifest.file_entry(&path).cloned() {
+                        manifest.revert(&entry)?;
+                        manifest.entries.retain(|e| !matches!(e, ManifestEntry::File { path: p, .. } if *p == path));
+                        manifest.save()?;
+                    }
+                } else {
+                    fs::remove_file(&path).map_err(|e| format!("Failed to remove {}: {}", path, e))?;
+                }
+
+                systemctl(&["daemon-reload"]);
+                println!("✓ Removed {}", path);
+                Ok(())
+            }
+
+            _ => Err("Usage: ./tenpod.rs service install|uninstall [--system]".to_string()),
+        }
+    }
+
+    /// Persistent per-VM state (UEFI variables, TPM) under /var/lib/tenpod/vms/<name>
+    fn vm_state_dir(&self) -> String {
+        format!("{}/vms/{}", STATE_DIR, self.config.name)
+    }
+
+    /// Sockets and pidfiles for a running VM, in $XDG_RUNTIME_DIR/tenpod/<name>
+    fn runtime_dir(&self) -> PathBuf {
+        std::env::var("XDG_RUNTIME_DIR").ok()
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir)
+        .join("tenpod")
+        .join(&self.config.name)
+    }
+
+    /// Log of this VM's sessions, in $XDG_STATE_HOME/tenpod (older ones rotate to .1, .2, ...)
+    fn log_path(&self) -> PathBuf {
+        std::env::var("XDG_STATE_HOME").ok()
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var("HOME").ok().map(|h| Path::new(&h).join(".local/state")))
+        .unwrap_or_else(std::env::temp_dir)
+        .join("tenpod")
+        .join(format!("{}.log", self.config.name))
+    }
+
+    /// Start the VM in the background: a detached copy of tenpod supervises QEMU, so the
+    /// usual cleanup (TPM, GPU rebinding) still happens when it exits
+    fn start_detached(&self) -> Result<(), String> {
+        if let Some(pid) = self.qemu_pid() {
+            return Err(format!("VM '{}' is already running as pid {}", self.config.name, pid));
+        }
+
+        let log = self.log_path();
+        rotate_logs(&log, LOG_ROTATIONS)?;
+
+        let file = fs::File::create(&log)
+        .map_err(|e| format!("Failed to create {}: {}", log.display(), e))?;
+        let stderr = file.try_clone().map_err(|e| e.to_string())?;
+
+        let tenpod = self_command()?;
+        let args: Vec<String> = std::env::args().skip(1).filter(|a| a != "--detach").collect();
+
+        // setsid puts the supervisor in its own session, so closing the terminal doesn't stop the VM
+        let mut child = Command::new("setsid")
+        .arg("--wait")
+        .args(&tenpod)
+        .args(&args)
+        .stdin(std::process::Stdio::null())
+        .stdout(file)
+        .stderr(stderr)
+        .spawn()
+        .map_err(|e| format!("Failed to start VM in the background: {}", e))?;
+
+        // Wait until QEMU is up, or the supervisor gave up
+        let deadline = Instant::now() + Duration::from_secs(120);
+        while Instant::now() < deadline {
+            if let Some(pid) = self.qemu_pid() {
+                println!("✓ VM '{}' started in the background (QEMU pid {})", self.config.name, pid);
+                println!("  Logs: ./tenpod.rs logs -f   ({})", log.display());
+                println!("  Stop: ./tenpod.rs stop");
+                return Ok(());
+            }
+
+            if let Ok(Some(_)) = child.try_wait() {
+                let output = fs::read_to_string(&log).unwrap_or_default();
+                let tail: Vec<&str> = output.lines().rev().take(15).collect();
+                let tail: Vec<&str> = tail.into_iter().rev().collect();
+                return Err(format!("VM exited during startup:\n{}", tail.join("\n")));
+            }
+
+            std::thread::sleep(Duration::from_millis(250));
+        }
+
+        Err(format!("VM did not come up within 2 minutes, see {}", log.display()))
+    }
+
+    /// Print this VM's log, or follow it with `-f`
+    fn logs(&self, follow: bool) -> Result<(), String> {
+        let log = self.log_path();
+        if !log.exists() {
+            return Err(format!("No log yet at {} (logs are written by start --detach)", log.display()));
+        }
+
+        if follow {
+            Command::new("tail")
+            .args(["-n", "50", "-F"])
+            .arg(&log)
+            .status()
+            .map_err(|e| format!("Failed to run tail: {}", e))?;
+        } else {
+            let text = fs::read_to_string(&log)
+            .map_err(|e| format!("Failed to read {}: {}", log.display(), e))?;
+            print!("{}", text);
+        }
+
+        Ok(())
+    }
+
+    /// QEMU's QMP socket for this VM
+    fn qmp_path(&self) -> PathBuf {
+        self.runtime_dir().join("qmp.sock")
+    }
+
+    /// Connect to the running VM's QMP socket
+    fn qmp(&self) -> Result<Qmp, String> {
+        if !self.qmp_path().exists() {
+            return Err(format!("VM '{}' is not running", self.config.name));
+        }
+        Qmp::connect(&self.qmp_path())
+    }
+
+    /// `firmware.type` with `auto` resolved. A disk without a UEFI variable store (with or without
+    /// Secure Boot) was installed under the old SeaBIOS default, and Windows on an MBR disk won't
+    /// boot from OVMF.
+    fn firmware_type(&self) -> &str {
+        match self.config.firmware.as_str() {
+            "auto" if self.config.secure_boot => "uefi",
+            "auto" if Path::new(&self.config.disk_path).exists() && !self.has_nvram(true) && !self.has_nvram(false) => "bios",
+            "auto" => "uefi",
+            other => other,
+        }
+    }
+
+    /// This VM's writable copy of the OVMF variable store
+    fn nvram_path(&self) -> String {
+        self.nvram_variant(self.config.secure_boot)
+    }
+
+    /// The variable store for the Secure Boot or the plain OVMF build
+    fn nvram_variant(&self, secure_boot: bool) -> String {
+        format!("{}/OVMF_VARS{}.fd", self.vm_state_dir(), if secure_boot { ".secboot" } else { "" })
+    }
+
+    fn has_nvram(&self, secure_boot: bool) -> bool {
+        Path::new(&self.nvram_variant(secure_boot)).exists()
+    }
+
+    /// Create this VM's TPM state directory, owned by the user who runs the VM
+    fn prepare_tpm_state(&self) -> Result<String, String> {
+        let dir = format!("{}/tpm", self.vm_state_dir());
+        if !Path::new(&dir).exists() {
+            let user = std::env::var("USER").unwrap_or_else(|_| "root".to_string());
+            let status = Command::new("pkexec")
+            .args(["install", "-d", "-m", "700", "-o", &user, &dir])
+            .status()
+            .map_err(|e| format!("Failed to create {}: {}", dir, e))?;
+
+            if !status.success() {
+                return Err(format!("Failed to create TPM state directory {}", dir));
+            }
+        }
+
+        Ok(dir)
+    }
+
+    /// Launch swtpm for this VM and wait for its control socket
+    fn start_tpm(&self) -> Result<PathBuf, String> {
+        if !have_program("swtpm") {
+            return Err("swtpm not found. Run ./tenpod install, or set tpm.enabled = false".to_string());
+        }
+
+        let state = self.prepare_tpm_state()?;
+        let runtime = self.runtime_dir();
+        fs::create_dir_all(&runtime)
+        .map_err(|e| format!("Failed to create {}: {}", runtime.display(), e))?;
+
+        // A swtpm left over from a crashed VM would hold the state lock
+        self.stop_tpm();
+
+        let socket = runtime.join("swtpm.sock");
+        fs::remove_file(&socket).ok();
+
+        // --terminate: swtpm exits as soon as QEMU closes the connection
+        let status = Command::new("swtpm")
+        .args(["socket", "--tpm2", "--daemon", "--terminate"])
+        .arg("--tpmstate").arg(format!("dir={}", state))
+        .arg("--ctrl").arg(format!("type=unixio,path={}", socket.display()))
+        .arg("--pid").arg(format!("file={}", runtime.join("swtpm.pid").display()))
+        .arg("--log").arg(format!("file={}", runtime.join("swtpm.log").display()))
+        .status()
+        .map_err(|e| format!("Failed to start swtpm: {}", e))?;
+
+        if !status.success() {
+            return Err(format!("swtpm failed to start, see {}", runtime.join("swtpm.log").display()));
+        }
+
+        for _ in 0..50 {
+            if socket.exists() {
+                return Ok(socket);
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+
+        Err(format!("swtpm did not create {}", socket.display()))
+    }
+
+    /// Stop this VM's swtpm if it is still running
+    fn stop_tpm(&self) {
+        let runtime = self.runtime_dir();
+        let pidfile = runtime.join("swtpm.pid");
+        let pid = fs::read_to_string(&pidfile).ok()
+        .and_then(|p| p.trim().parse::<u32>().ok());
+
+        if let Some(pid) = pid {
+            let comm = fs::read_to_string(format!("/proc/{}/comm", pid)).unwrap_or_default();
+            if comm.trim() == "swtpm" {
+                Command::new("kill").arg(pid.to_string()).status().ok();
+            }
+        }
+
+        fs::remove_file(pidfile).ok();
+        fs::remove_file(runtime.join("swtpm.sock")).ok();
+    }
+
+    /// The configured OVMF files, or the distribution's build
+    fn find_ovmf(&self) -> Result<Ovmf, String> {
+        match (&self.config.ovmf_code, &self.config.ovmf_vars) {
+            (Some(code), Some(vars)) => Ok(Ovmf { code: code.clone(), vars: vars.clone(), secure_boot: self.config.secure_boot }),
+            _ => Ovmf::find(self.config.secure_boot).ok_or_else(|| {
+                format!(
+                    "{}OVMF firmware not found. Install it (edk2-ovmf / ovmf) or set firmware.ovmf_code and firmware.ovmf_vars",
+                    if self.config.secure_boot { "Secure Boot " } else { "" }
+                )
+            }),
+        }
+    }
+
+    /// Find OVMF and make sure this VM has its own VARS file, copying the template if needed
+    fn prepare_nvram(&self) -> Result<(Ovmf, String), String> {
+        let firmware = self.find_ovmf()?;
+
+        let nvram = self.nvram_path();
+        if !Path::new(&nvram).exists() {
+            // The two builds can't share a store, so the guest's boot entries stay in the other one
+            if self.has_nvram(!self.config.secure_boot) {
+                println!(
+                    "⚠️  Secure Boot was turned {}: starting with fresh UEFI variables (OVMF finds Windows' boot loader by itself)",
+                    if self.config.secure_boot { "on" } else { "off" }
+                );
+            }
+            let user = std::env::var("USER").unwrap_or_else(|_| "root".to_string());
+            let status = Command::new("pkexec")
+            .args(["install", "-D", "-m", "644", "-o", &user, &firmware.vars, &nvram])
+            .status()
+            .map_err(|e| format!("Failed to create {}: {}", nvram, e))?;
+
+            if !status.success() {
+                return Err(format!("Failed to create UEFI variable store {}", nvram));
+            }
+        }
+
+        Ok((firmware, nvram))
+    }
+
+    /// Whether every function of the GPU is already bound to vfio-pci
+    fn gpu_on_vfio(&self) -> bool {
+        self.gpu_functions.iter()
+        .all(|f| self.pci.device(&f.address).and_then(|d| d.driver).as_deref() == Some("vfio-pci"))
+    }
+
+    /// Device nodes a host process could be holding open on this GPU
+    fn gpu_device_nodes(&self) -> Vec<String> {
+        let mut nodes = Vec::new();
+
+        for function in &self.gpu_functions {
+            let drm = self.pci.devices_dir().join(&function.address).join("drm");
+            if let Ok(entries) = fs::read_dir(drm) {
+                for entry in entries.filter_map(|e| e.ok()) {
+                    let name = entry.file_name().to_string_lossy().to_string();
+                    if name.starts_with("card") || name.starts_with("renderD") {
+                        nodes.push(format!("/dev/dri/{}", name));
+                    }
+                }
+            }
+        }
+
+        let gpu = self.gpu_functions.iter().find(|f| f.address == self.gpu_pci);
+        if gpu.and_then(|g| self.pci.device(&g.address)).and_then(|d| d.driver).as_deref() == Some("nvidia") {
+            let info = format!("/proc/driver/nvidia/gpus/{}/information", self.gpu_pci);
+            let minor = fs::read_to_string(info).ok().and_then(|text| {
+                text.lines()
+                .find(|l| l.starts_with("Device Minor"))
+                .and_then(|l| l.split(':').nth(1))
+                .map(|m| m.trim().to_string())
+            });
+
+            if let Some(minor) = minor {
+                nodes.push(format!("/dev/nvidia{}", minor));
+            }
+
+            // The control nodes are shared by every NVIDIA GPU; only ours if the host has no other
+            if !self.host_uses_vendor_driver {
+                for node in ["/dev/nvidiactl", "/dev/nvidia-modeset", "/dev/nvidia-uvm"] {
+                    nodes.push(node.to_string());
+                }
+            }
+        }
+
+        nodes
+    }
+
+    /// Detach the GPU from its host driver and give it to vfio-pci, no reboot needed
+    fn bind_vfio(&self) -> Result<(), String> {
+        println!("🔗 Binding GPU {} to vfio-pci...", self.gpu_pci);
+
+        let nodes = self.gpu_device_nodes();
+        let (mut holders, complete) = processes_using(&nodes);
+
+        // Other users' processes (the display manager, persistenced) are only visible to root
+        if !complete && holders.is_empty() {
+            holders = processes_using_as_root(&nodes).map_err(|e| {
+                format!(
+                    "Could not check whether root-owned processes hold the GPU: {}\n\
+Not unbinding, the host could hang if the driver is still in use.",
+                    e
+                )
+            })?;
+        }
+
+        if !holders.is_empty() {
+            let list: Vec<String> = holders.iter()
+            .map(|(pid, name, node)| format!("  {} {} ({})", pid, name, node))
+            .collect();
+            return Err(format!(
+                "The GPU is still in use by these processes:
+{}
+Close them (or log out of any desktop session running on this GPU) and try again.",
+                list.join("\n")
+            ));
+        }
+
+        let devices = self.pci.devices_dir();
+        let mut script = String::from("modprobe vfio-pci || exit 1\n");
+        for function in &self.gpu_functions {
+            let dir = devices.join(&function.address);
+            script.push_str(&format!(
+                "echo vfio-pci > {dir}/driver_override\n\
+[ -e {dir}/driver ] && echo {addr} > {dir}/driver/unbind\n\
+echo {addr} > {probe}\n",
+                dir = dir.display(),
+                addr = function.address,
+                probe = self.pci.root.join("bus/pci/drivers_probe").display()
+            ));
+        }
+
+        run_root_script(&script)?;
+
+        for function in &self.gpu_functions {
+            let driver = self.pci.device(&function.address).and_then(|d| d.driver);
+            match driver.as_deref() {
+                Some("vfio-pci") => println!("  ✓ {} {} → vfio-pci", function.role(), function.address),
+                other => {
+                    return Err(format!(
+                        "{} is on {} instead of vfio-pci after binding",
+                        function.address,
+                        other.unwrap_or("no driver")
+                    ))
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Release the GPU from vfio-pci and let the kernel re-probe its normal host driver
+    fn unbind_vfio(&self) -> Result<(), String> {
+        println!("🔗 Returning GPU {} to its host driver...", self.gpu_pci);
+
+        let devices = self.pci.devices_dir();
+        let mut script = String::new();
+        for function in &self.gpu_functions {
+            let dir = devices.join(&function.address);
+            script.push_str(&format!(
+                "echo > {dir}/driver_override\n\
+[ -e {dir}/driver ] && echo {addr} > {dir}/driver/unbind\n\
+echo {addr} > {probe}\n",
+                dir = dir.display(),
+                addr = function.address,
+                probe = self.pci.root.join("bus/pci/drivers_probe").display()
+            ));
+        }
+
+        run_root_script(&script)?;
+
+        for function in &self.gpu_functions {
+            let driver = self.pci.device(&function.address).and_then(|d| d.driver);
+            println!("  ✓ {} {} → {}", function.role(), function.address, driver.as_deref().unwrap_or("no driver"));
+        }
+
+        if self.pci.device(&self.gpu_pci).and_then(|d| d.driver).as_deref() == Some("vfio-pci") {
+            return Err("GPU is still bound to vfio-pci (is vfio-pci ids= set in /etc/modprobe.d/vfio.conf?)".to_string());
+        }
+
+        Ok(())
+    }
+
+    /// Stop the VM; `kill` skips the clean shutdown
+    fn stop(&self, kill: bool) -> Result<(), String> {
+        println!("🛑 Stopping Ten Pod VM...");
+
+        if self.config.backend == "libvirt" {
+            if kill {
+                virsh(&["destroy", &self.config.name])?;
+                println!("✓ VM stopped");
+            } else {
+                virsh(&["shutdown", &self.config.name])?;
+                println!("✓ Shutdown requested, Windows will power off the VM");
+            }
+            return Ok(());
+        }
+
+        let pid = self.qemu_pid()
+        .ok_or_else(|| format!("VM '{}' is not running", self.config.name))?;
+
+        // Press the ACPI power button so Windows shuts down cleanly
+        let mut stopped = false;
+        if !kill {
+            match self.qmp().and_then(|mut qmp| qmp.execute("system_powerdown", None)) {
+                Ok(_) => {
+                    println!("  Waiting up to {}s for Windows to shut down...", self.config.shutdown_timeout);
+                    stopped = wait_for_exit(pid, Duration::from_secs(self.config.shutdown_timeout));
+                    if !stopped {
+                        println!("⚠️  Guest did not shut down in time");
+                    }
+                }
+                Err(e) => println!("⚠️  Could not request a clean shutdown: {}", e),
+            }
+        }
+
+        // Then escalate: SIGTERM lets QEMU exit on its own, SIGKILL if even that hangs
+        for signal in ["TERM", "KILL"] {
+            if stopped {
+                break;
+            }
+            println!("  Sending SIG{} to QEMU (pid {})", signal, pid);
+            Command::new("kill")
+            .arg(format!("-{}", signal))
+            .arg(pid.to_string())
+            .status()
+            .ok();
+            stopped = wait_for_exit(pid, Duration::from_secs(10));
+        }
+
+        if !stopped {
+            return Err(format!("QEMU (pid {}) is still running", pid));
+        }
+
+        self.stop_tpm();
+
+        println!("✓ VM stopped");
+        Ok(())
+    }
+
+    /// PID of this VM's QEMU process, if the pidfile points at a live QEMU
+    fn qemu_pid(&self) -> Option<u32> {
+        let pid = fs::read_to_string(self.runtime_dir().join("qemu.pid")).ok()
+        .and_then(|p| p.trim().parse::<u32>().ok())?;
+
+        let comm = fs::read_to_string(format!("/proc/{}/comm", pid)).unwrap_or_default();
+        if comm.starts_with("qemu") {
+            Some(pid)
+        } else {
+            None
+        }
+    }
+
+    /// Pause, resume or reset the running VM through QMP
+    fn control(&self, action: &str) -> Result<(), String> {
+        if self.config.backend == "libvirt" {
+            let command = match action {
+                "pause" => "suspend",
+                "resume" => "resume",
+                _ => "reset",
+            };
+            virsh(&[command, &self.config.name])?;
+            println!("✓ VM {}", if action == "reset" { "reset" } else { command });
+            return Ok(());
+        }
+
+        let (command, done) = match action {
+            "pause" => ("stop", "⏸️  VM paused"),
+            "resume" => ("cont", "▶️  VM resumed"),
+            "reset" => ("system_reset", "🔁 VM reset"),
+            _ => return Err(format!("Unknown action '{}'", action)),
+        };
+
+        self.qmp()?.execute(command, None)?;
+        println!("{}", done);
+        Ok(())
+    }
+
+    /// Show comprehensive status
+    fn status(&self) {
+        println!("\n📊 TEN POD STATUS\n");
+        if let Ok(gpus) = self.detect_gpus() {
+            println!("Detected GPUs:");
+            for gpu in &gpus {
+                let marker = if gpu.address == self.gpu_pci { "→" } else { " " };
+                println!("  {} {}: {}", marker, gpu.vendor.name(), gpu.describe());
+            }
+            println!();
+        }
+
+        println!("GPU Configuration:");
+        println!("  Video: {}", self.gpu_pci);
+        for function in self.gpu_functions.iter().filter(|f| f.address != self.gpu_pci) {
+            println!("  {}: {}", function.role(), function.address);
+        }
+        println!("  Memory: {}GB", self.config.memory_gb);
+        match self.cpu_plan() {
+            Ok(plan) => println!(
+                "  vCPUs: {} on host CPUs {} (emulator on {})",
+                plan.smp(), format_cpu_list(&plan.vcpus), format_cpu_list(&plan.emulator)
+            ),
+            Err(e) => println!("  vCPUs: ⚠️  {}", e),
+        }
+
+        if self.config.backend == "libvirt" {
+            let state = virsh(&["domstate", &self.config.name])
+            .unwrap_or_else(|_| "not defined".to_string());
+            println!("\nlibvirt domain '{}': {}", self.config.name, state.trim());
+        } else {
+            match self.qmp().and_then(|mut qmp| qmp.run_state().map(|state| (state, qmp))) {
+                Ok((state, mut qmp)) => {
+                    // The socket is created when QEMU starts, so its age is the VM's uptime
+                    let uptime = fs::metadata(self.qmp_path()).ok()
+                    .and_then(|m| m.modified().ok())
+                    .and_then(|t| t.elapsed().ok())
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0);
+                    println!(
+                        "\nVM '{}': {} (up {}h {:02}m)",
+                        self.config.name, state, uptime / 3600, uptime % 3600 / 60
+                    );
+                    let kvm = qmp.kvm_enabled().unwrap_or(false);
+                    println!("  QEMU {}, KVM {}", qmp.version, if kvm { "enabled" } else { "disabled" });
+                    if let Ok(threads) = qmp.vcpu_threads() {
+                        println!("  vCPU threads: {:?}", threads);
+                    }
+                }
+                Err(_) => println!("\nVM '{}': not running", self.config.name),
+            }
+        }
+
+        println!("\nInstallation:");
+        let manifest = Manifest::load();
+        if Manifest::exists() {
+            println!("  Manifest: {} ({} changes recorded)", MANIFEST_PATH, manifest.entries.len());
+        } else {
+            println!("  Manifest: none (run ./tenpod.rs install)");
+        }
+
+        for path in ["/etc/modprobe.d/vfio.conf", "/etc/modprobe.d/vfio-priority.conf"] {
+            let current = match fs::read(path) {
+                Ok(data) => data,
+                Err(_) => continue,
+            };
+
+            match manifest.file_entry(path) {
+                Some(ManifestEntry::File { checksum: sum, .. }) if *sum == checksum(&current) => {
+                    println!("  {}: managed by Ten Pod", path)
+                }
+                Some(_) => println!("  {}: ⚠️  modified since Ten Pod wrote it", path),
+                None => println!("  {}: user-configured (not managed by Ten Pod)", path),
+            }
         }
-        println!("  Memory: {}GB", self.memory_gb);
-        println!("  CPU Cores: {}", self.cpu_cores);
 
         let headsets = self.detect_headsets();
-        self.check_system().ok();
+        if let Err(e) = self.check_system() {
+            println!("\n⚠️  {}", e);
+        }
+
+        if !headsets.is_empty() {
+            println!("\n🎧 {} VR headset(s) ready for passthrough", headsets.len());
+        }
+    }
+}
+
+// ============================================================================
+// PCI BUS - Native device enumeration from sysfs
+// ============================================================================
+
+/// A PCI device as described by /sys/bus/pci/devices/<address>
+#[derive(Clone)]
+struct PciDevice {
+    address: String,
+    vendor: String,
+    device: String,
+    class: u32,
+    driver: Option<String>,
+    iommu_group: Option<String>,
+    /// Whether this device drives the boot framebuffer (the host console)
+    boot_vga: bool,
+}
+
+impl PciDevice {
+    /// "vendor:device" as used by vfio-pci ids=
+    fn id(&self) -> String {
+        format!("{}:{}", self.vendor, self.device)
+    }
+
+    /// Display controller (VGA, 3D or other)
+    fn is_display(&self) -> bool {
+        self.class >> 16 == 0x03
+    }
+
+    /// PCI-to-PCI bridges (class 0x0604) can stay with the host
+    fn is_bridge(&self) -> bool {
+        self.class >> 8 == 0x0604
+    }
+
+    /// High definition audio controller (class 0x0403)
+    fn is_audio(&self) -> bool {
+        self.class >> 8 == 0x0403
+    }
+
+    /// What this function is on a graphics card
+    fn role(&self) -> &'static str {
+        match self.class >> 8 {
+            0x0300 | 0x0302 | 0x0380 => "Video",
+            0x0403 => "Audio",
+            0x0c03 => "USB",
+            0x0c80 => "USB-C (UCSI)",
+            _ => "Function",
+        }
+    }
+}
+
+/// Reads PCI devices and IOMMU groups from sysfs. The root is injectable
+/// so a fake sysfs tree can stand in for /sys.
+struct PciBus {
+    root: PathBuf,
+}
+
+impl PciBus {
+    fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn system() -> Self {
+        Self::new("/sys")
+    }
+
+    fn devices_dir(&self) -> PathBuf {
+        self.root.join("bus/pci/devices")
+    }
+
+    fn groups_dir(&self) -> PathBuf {
+        self.root.join("kernel/iommu_groups")
+    }
+
+    /// Kernel module behind a PCI driver (driver `xhci_hcd` lives in module `xhci_pci`), None
+    /// for drivers built into the kernel
+    fn driver_module(&self, driver: &str) -> Option<String> {
+        fs::read_link(self.root.join("bus/pci/drivers").join(driver).join("module")).ok()
+        .and_then(|link| link.file_name().and_then(|n| n.to_str()).map(|n| n.to_string()))
+    }
+
+    /// Every PCI device, sorted by address
+    fn devices(&self) -> Vec<PciDevice> {
+        let mut devices: Vec<PciDevice> = fs::read_dir(self.devices_dir())
+        .map(|entries| {
+            entries.filter_map(|e| e.ok())
+            .filter_map(|e| e.file_name().to_str().map(|n| n.to_string()))
+            .filter_map(|addr| self.device(&addr))
+            .collect()
+        })
+        .unwrap_or_default();
+        devices.sort_by(|a, b| a.address.cmp(&b.address));
+        devices
+    }
+
+    /// A single device by its full address (0000:01:00.0)
+    fn device(&self, addr: &str) -> Option<PciDevice> {
+        let dir = self.devices_dir().join(addr);
+        let read = |attr: &str| fs::read_to_string(dir.join(attr)).ok().map(|v| v.trim().to_string());
+        let link_name = |attr: &str| {
+            fs::read_link(dir.join(attr)).ok()
+            .and_then(|link| link.file_name().and_then(|n| n.to_str()).map(|n| n.to_string()))
+        };
+
+        let vendor = read("vendor")?;
+        let device = read("device")?;
+        let class = read("class")?;
+
+        Some(PciDevice {
+            address: addr.to_string(),
+            vendor: vendor.trim_start_matches("0x").to_lowercase(),
+            device: device.trim_start_matches("0x").to_lowercase(),
+            class: u32::from_str_radix(class.trim_start_matches("0x"), 16).ok()?,
+            driver: link_name("driver"),
+            iommu_group: link_name("iommu_group"),
+            boot_vga: read("boot_vga").map(|v| v == "1").unwrap_or(false),
+        })
+    }
+
+    /// Every function in the same slot as `addr` (a card's video, audio, USB...), in order
+    fn slot_functions(&self, addr: &str) -> Vec<PciDevice> {
+        let slot = pci_slot(addr);
+        self.devices().into_iter()
+        .filter(|d| pci_slot(&d.address) == slot)
+        .collect()
+    }
+
+    /// Number of IOMMU groups (zero when IOMMU is off)
+    fn iommu_group_count(&self) -> usize {
+        fs::read_dir(self.groups_dir())
+        .map(|entries| entries.count())
+        .unwrap_or(0)
+    }
+
+    /// PCI addresses of every device in an IOMMU group
+    fn iommu_group_devices(&self, group: &str) -> Vec<String> {
+        let mut devices: Vec<String> = fs::read_dir(self.groups_dir().join(group).join("devices"))
+        .map(|entries| {
+            entries.filter_map(|e| e.ok())
+            .filter_map(|e| e.file_name().to_str().map(|n| n.to_string()))
+            .collect()
+        })
+        .unwrap_or_default();
+        devices.sort();
+        devices
+    }
+}
+
+/// Domain, bus and slot of an address: "0000:01:00.1" -> "0000:01:00"
+fn pci_slot(addr: &str) -> &str {
+    addr.rsplit_once('.').map(|(slot, _)| slot).unwrap_or(addr)
+}
+
+/// Human-readable device name from lspci when pciutils is installed, else the raw ids
+fn pci_device_name(device: &PciDevice) -> String {
+    lspci_name(&device.address)
+    .unwrap_or_else(|| format!("[{}] class {:06x}", device.id(), device.class))
+}
+
+/// Device name as lspci reports it (display only, never parsed for ids)
+fn lspci_name(addr: &str) -> Option<String> {
+    Command::new("lspci")
+    .args(["-s", addr])
+    .output()
+    .ok()
+    .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
+    .and_then(|line| line.split_once(' ').map(|(_, name)| name.to_string()))
+    .filter(|name| !name.is_empty())
+}
+
+/// Processes holding any of the given device nodes open: (pid, command name, node), and
+/// whether every process could be inspected (other users' fds are only visible to root)
+fn processes_using(nodes: &[String]) -> (Vec<(u32, String, String)>, bool) {
+    let mut found = Vec::new();
+    let mut complete = true;
+    if nodes.is_empty() {
+        return (found, complete);
+    }
+
+    let procs = match fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(_) => return (found, false),
+    };
+
+    for entry in procs.filter_map(|e| e.ok()) {
+        let pid: u32 = match entry.file_name().to_str().and_then(|n| n.parse().ok()) {
+            Some(pid) => pid,
+            None => continue,
+        };
+
+        let fds = match fs::read_dir(entry.path().join("fd")) {
+            Ok(fds) => fds,
+            Err(e) => {
+                if e.kind() == io::ErrorKind::PermissionDenied {
+                    complete = false;
+                }
+                continue;
+            }
+        };
+
+        for fd in fds.filter_map(|e| e.ok()) {
+            let target = match fs::read_link(fd.path()) {
+                Ok(target) => target.to_string_lossy().to_string(),
+                Err(_) => continue,
+            };
+
+            if nodes.contains(&target) {
+                let name = fs::read_to_string(entry.path().join("comm"))
+                .map(|c| c.trim().to_string())
+                .unwrap_or_default();
+                found.push((pid, name, target));
+                break;
+            }
+        }
+    }
+
+    (found, complete)
+}
+
+/// The same scan run as root, so Xorg, gdm or nvidia-persistenced holding the GPU are seen too
+fn processes_using_as_root(nodes: &[String]) -> Result<Vec<(u32, String, String)>, String> {
+    let mut cmd = Command::new("pkexec");
+    cmd.arg("sh")
+    .arg("-c")
+    // Processes exiting mid-scan make find fail, that's not an error here
+    .arg(r#"find /proc/[0-9]*/fd -maxdepth 1 \( "$@" \) -printf '%h %l\n' 2>/dev/null; exit 0"#)
+    .arg("sh");
+    for (i, node) in nodes.iter().enumerate() {
+        if i > 0 {
+            cmd.arg("-o");
+        }
+        cmd.args(["-lname", node]);
+    }
+
+    let output = cmd.output().map_err(|e| format!("Failed to run pkexec: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("pkexec was not authorized ({})", output.status));
+    }
+
+    let mut found: Vec<(u32, String, String)> = Vec::new();
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        // "/proc/1234/fd /dev/nvidia0"
+        let (dir, node) = match line.split_once(' ') {
+            Some(parts) => parts,
+            None => continue,
+        };
+        let pid: u32 = match dir.split('/').nth(2).and_then(|p| p.parse().ok()) {
+            Some(pid) => pid,
+            None => continue,
+        };
+        if found.iter().any(|(p, _, _)| *p == pid) {
+            continue;
+        }
+        let name = fs::read_to_string(format!("/proc/{}/comm", pid))
+        .map(|c| c.trim().to_string())
+        .unwrap_or_default();
+        found.push((pid, name, node.to_string()));
+    }
+    Ok(found)
+}
+
+/// System-wide systemd units
+const SYSTEM_UNIT_DIR: &str = "/etc/systemd/system";
+
+/// How to run tenpod again later. Under rust-script that is the script itself: the cached binary
+/// we are running from is rebuilt under a new name, and the old one deleted, whenever tenpod.rs
+/// changes. rust-script is named explicitly since services don't have ~/.cargo/bin on their PATH.
+fn self_command() -> Result<Vec<String>, String> {
+    if let Some(script) = std::env::var("RUST_SCRIPT_PATH").ok().filter(|p| !p.is_empty()) {
+        let runner = std::env::var_os("PATH").iter()
+        .flat_map(std::env::split_paths)
+        .map(|dir| dir.join("rust-script"))
+        .find(|path| path.exists());
+        return Ok(match runner {
+            Some(runner) => vec![runner.display().to_string(), script],
+            None => vec![script],
+        });
+    }
+
+    let exe = std::env::current_exe()
+    .map_err(|e| format!("Cannot find the tenpod executable: {}", e))?;
+    Ok(vec![exe.display().to_string()])
+}
+
+/// Quote an argument for a systemd Exec= line when it needs it
+fn systemd_quote(arg: &str) -> String {
+    let arg = arg.replace('%', "%%");
+    if !arg.is_empty() && !arg.contains(|c: char| c.is_whitespace() || "\"'\\;$".contains(c)) {
+        return arg;
+    }
+    format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\"").replace('$', "$$"))
+}
+
+/// Number of previous session logs kept next to the current one
+const LOG_ROTATIONS: u32 = 5;
+
+/// Shift `log` to `log.1`, `log.1` to `log.2`, ..., dropping the oldest
+fn rotate_logs(log: &Path, keep: u32) -> Result<(), String> {
+    if let Some(dir) = log.parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    }
+
+    let numbered = |n: u32| PathBuf::from(format!("{}.{}", log.display(), n));
+    for n in (1..keep).rev() {
+        fs::rename(numbered(n), numbered(n + 1)).ok();
+    }
+    if keep > 0 {
+        fs::rename(log, numbered(1)).ok();
+    }
+
+    Ok(())
+}
+
+/// Wait for a process to exit, up to `timeout`
+fn wait_for_exit(pid: u32, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    let proc_dir = format!("/proc/{}", pid);
+
+    while Path::new(&proc_dir).exists() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        std::thread::sleep(Duration::from_millis(250));
+    }
+    true
+}
+
+/// Why a pkexec call failed. 126 and 127 mean polkit refused: the password prompt was dismissed,
+/// or there was no polkit agent to show it (as in a systemd service).
+fn pkexec_failure(status: std::process::ExitStatus) -> String {
+    match status.code() {
+        Some(126) | Some(127) => "not authorized (no password given, or no polkit agent to ask for it)".to_string(),
+        Some(code) => format!("pkexec exited with status {}", code),
+        None => "pkexec was killed".to_string(),
+    }
+}
+
+/// Run a shell script as root through pkexec
+fn run_root_script(script: &str) -> Result<(), String> {
+    let status = Command::new("pkexec")
+    .arg("sh")
+    .arg("-c")
+    .arg(script)
+    .status()
+    .map_err(|e| format!("Failed to run pkexec: {}", e))?;
+
+    if !status.success() {
+        return Err("Privileged operation failed (authentication cancelled?)".to_string());
+    }
+    Ok(())
+}
+
+// ============================================================================
+// CPU TOPOLOGY - Host cores, SMT siblings and cache domains from sysfs
+// ============================================================================
+
+/// Host slices confined to the emulator CPUs while an isolated VM runs
+const ISOLATED_UNITS: &[&str] = &["system.slice", "user.slice", "init.scope"];
+
+/// A physical core: its logical CPUs (SMT siblings) and where it sits
+#[derive(Clone)]
+struct HostCore {
+    cpus: Vec<u32>,
+    package: u32,
+    /// CPUs sharing this core's L3 cache (a CCX on AMD), empty if unknown
+    l3: Vec<u32>,
+    /// Intel hybrid E-core
+    efficiency: bool,
+}
+
+/// Host CPU layout as described by /sys/devices/system/cpu and /sys/devices/system/node
+struct CpuTopology {
+    online: Vec<u32>,
+    cores: Vec<HostCore>,
+    /// NUMA nodes and their CPUs
+    nodes: Vec<(u32, Vec<u32>)>,
+}
+
+impl CpuTopology {
+    /// Read the topology below a sysfs root (normally /sys)
+    fn new<P: Into<PathBuf>>(root: P) -> Self {
+        let root = root.into();
+        let cpu_dir = root.join("devices/system/cpu");
+        let read_list = |path: PathBuf| {
+            fs::read_to_string(path).ok()
+            .and_then(|text| parse_cpu_list(text.trim()).ok())
+            .unwrap_or_default()
+        };
+
+        let online = read_list(cpu_dir.join("online"));
+        let atoms = read_list(root.join("devices/cpu_atom/cpus"));
+        let mut cores: Vec<HostCore> = Vec::new();
+
+        for &cpu in &online {
+            let dir = cpu_dir.join(format!("cpu{}", cpu));
+            let mut siblings = read_list(dir.join("topology/thread_siblings_list"));
+            if siblings.is_empty() {
+                siblings.push(cpu);
+            }
+            siblings.retain(|c| online.contains(c));
+
+            if cores.iter().any(|core| core.cpus.contains(&cpu)) {
+                continue;
+            }
+
+            let package = fs::read_to_string(dir.join("topology/physical_package_id")).ok()
+            .and_then(|p| p.trim().parse().ok())
+            .unwrap_or(0);
+
+            let l3 = (0..8)
+            .map(|i| dir.join(format!("cache/index{}", i)))
+            .find(|index| fs::read_to_string(index.join("level")).map(|l| l.trim() == "3").unwrap_or(false))
+            .map(|index| read_list(index.join("shared_cpu_list")))
+            .unwrap_or_default();
+
+            cores.push(HostCore { cpus: siblings, package, l3, efficiency: atoms.contains(&cpu) });
+        }
+
+        let node_dir = root.join("devices/system/node");
+        let nodes = read_list(node_dir.join("online")).into_iter()
+        .map(|node| (node, read_list(node_dir.join(format!("node{}/cpulist", node)))))
+        .collect();
+
+        Self { online, cores, nodes }
+    }
+
+    fn system() -> Self {
+        Self::new("/sys")
+    }
+
+    /// Cores grouped by shared L3 cache, in CPU order
+    fn cache_domains(&self) -> Vec<Vec<&HostCore>> {
+        let mut domains: Vec<Vec<&HostCore>> = Vec::new();
+        for core in &self.cores {
+            match domains.iter_mut().find(|d| d[0].l3 == core.l3 && d[0].package == core.package) {
+                Some(domain) => domain.push(core),
+                None => domains.push(vec![core]),
+            }
+        }
+        domains
+    }
+
+    /// NUMA node a CPU belongs to, None when the host has a single node
+    fn node_of(&self, cpu: u32) -> Option<u32> {
+        if self.nodes.len() < 2 {
+            return None;
+        }
+        self.nodes.iter().find(|(_, cpus)| cpus.contains(&cpu)).map(|(node, _)| *node)
+    }
+
+    /// Pick `count` whole cores for the guest: performance cores only, never CPU 0, only from
+    /// `node` if given, and all from one cache domain when possible, preferring a domain the
+    /// host isn't using
+    fn select_cores(&self, count: usize, node: Option<u32>) -> Result<(Vec<&HostCore>, bool), String> {
+        let has_performance = self.cores.iter().any(|c| !c.efficiency);
+        let on_node = |core: &HostCore| node.is_none() || self.node_of(core.cpus[0]) == node;
+        let eligible = |core: &&HostCore| {
+            !core.cpus.contains(&0) && (!core.efficiency || !has_performance) && on_node(core)
+        };
+
+        let mut domains: Vec<Vec<&HostCore>> = self.cache_domains().into_iter()
+        .map(|d| d.into_iter().filter(eligible).collect::<Vec<_>>())
+        .filter(|d| !d.is_empty())
+        .collect();
+
+        // Domains without CPU 0 first (the host keeps its own cache), then the biggest
+        domains.sort_by_key(|d| (d[0].l3.contains(&0), std::cmp::Reverse(d.len())));
+
+        if let Some(domain) = domains.iter().find(|d| d.len() >= count) {
+            let mut chosen: Vec<&HostCore> = domain[domain.len() - count..].to_vec();
+            chosen.sort_by_key(|c| c.cpus[0]);
+            return Ok((chosen, true));
+        }
+
+        // Spread over several domains, still taking the highest-numbered cores of each
+        let mut chosen: Vec<&HostCore> = domains.into_iter().flat_map(|d| d.into_iter().rev()).take(count).collect();
+        if chosen.len() < count {
+            return Err(format!(
+                "vm.cores = {} but the host only has {} cores to spare{} (CPU 0's core stays with the host)",
+                count,
+                chosen.len(),
+                node.map(|n| format!(" on NUMA node {}", n)).unwrap_or_default()
+            ));
+        }
+        chosen.sort_by_key(|c| c.cpus[0]);
+        Ok((chosen, false))
+    }
+}
+
+/// Which host CPU runs each vCPU, where everything else runs, and the guest topology
+struct CpuPlan {
+    /// Host CPU for vCPU 0, 1, ...
+    vcpus: Vec<u32>,
+    /// Host CPUs for QEMU's main loop and I/O threads
+    emulator: Vec<u32>,
+    sockets: u32,
+    cores: u32,
+    threads: u32,
+    /// Whether all vCPUs share one L3 cache
+    one_cache: bool,
+    /// NUMA node guest memory is bound to
+    node: Option<u32>,
+}
+
+impl CpuPlan {
+    /// QEMU `-smp` value for the guest topology
+    fn smp(&self) -> String {
+        format!("{},sockets={},cores={},threads={}", self.vcpus.len(), self.sockets, self.cores, self.threads)
+    }
+}
+
+/// Render CPUs as a compact list, e.g. [4, 5, 6, 7, 12] -> "4-7,12"
+fn format_cpu_list(cpus: &[u32]) -> String {
+    let mut sorted = cpus.to_vec();
+    sorted.sort_unstable();
+    sorted.dedup();
+
+    let mut parts = Vec::new();
+    let mut i = 0;
+    while i < sorted.len() {
+        let start = sorted[i];
+        while i + 1 < sorted.len() && sorted[i + 1] == sorted[i] + 1 {
+            i += 1;
+        }
+        parts.push(if sorted[i] == start { start.to_string() } else { format!("{}-{}", start, sorted[i]) });
+        i += 1;
+    }
+    parts.join(",")
+}
+
+/// Pin one thread to a set of host CPUs
+fn pin_thread(tid: u64, cpus: &[u32]) -> Result<(), String> {
+    let status = Command::new("taskset")
+    .args(["-pc", &format_cpu_list(cpus), &tid.to_string()])
+    .stdout(std::process::Stdio::null())
+    .stderr(std::process::Stdio::null())
+    .status()
+    .map_err(|e| format!("Failed to run taskset: {}", e))?;
+
+    if !status.success() {
+        return Err(format!("taskset could not pin thread {}", tid));
+    }
+    Ok(())
+}
+
+// ============================================================================
+// HUGEPAGES - Allocated when the VM starts and given back when it exits
+// ============================================================================
+
+/// Where hugetlbfs is mounted for 2MB pages (systemd's dev-hugepages.mount)
+const HUGETLBFS: &str = "/dev/hugepages";
+
+/// Mounted by `start` when 1GB pages are used
+const HUGETLBFS_1G: &str = "/dev/hugepages1G";
+
+const HUGEPAGE_2M_KB: u64 = 2048;
+const HUGEPAGE_1G_KB: u64 = 1024 * 1024;
+
+/// Hugepage pools in sysfs, system-wide and per NUMA node
+struct HugePages {
+    root: PathBuf,
+}
+
+/// The hugepages one VM run uses
+struct HugePagePool {
+    size_kb: u64,
+    /// NUMA node the pages come from, None on single-node hosts
+    node: Option<u32>,
+    pages: u64,
+    /// Pages this run added to the pool, given back when the VM exits
+    added: u64,
+    /// Whether this run mounted the hugetlbfs, unmounted again when the VM exits
+    mounted: bool,
+}
+
+impl HugePagePool {
+    /// hugetlbfs mount with this pool's page size
+    fn mount_point(&self) -> &'static str {
+        if self.size_kb == HUGEPAGE_1G_KB { HUGETLBFS_1G } else { HUGETLBFS }
+    }
+
+    fn describe(&self) -> String {
+        let size = if self.size_kb >= 1024 * 1024 {
+            format!("{}GB", self.size_kb / (1024 * 1024))
+        } else {
+            format!("{}MB", self.size_kb / 1024)
+        };
+        match self.node {
+            Some(node) => format!("{} x {} hugepages on NUMA node {}", self.pages, size, node),
+            None => format!("{} x {} hugepages", self.pages, size),
+        }
+    }
+}
+
+impl HugePages {
+    /// Pools below a sysfs root (normally /sys)
+    fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn system() -> Self {
+        Self::new("/sys")
+    }
+
+    fn pool_dir(&self, pool: &HugePagePool) -> PathBuf {
+        let base = match pool.node {
+            Some(node) => self.root.join(format!("devices/system/node/node{}/hugepages", node)),
+            None => self.root.join("kernel/mm/hugepages"),
+        };
+        base.join(format!("hugepages-{}kB", pool.size_kb))
+    }
+
+    /// A pool counter such as nr_hugepages or free_hugepages, 0 if unreadable
+    fn read(&self, pool: &HugePagePool, counter: &str) -> u64 {
+        fs::read_to_string(self.pool_dir(pool).join(counter)).ok()
+        .and_then(|n| n.trim().parse().ok())
+        .unwrap_or(0)
+    }
+
+    /// Whether the CPU and kernel support pages of this size (1GB needs pdpe1gb)
+    fn supported(&self, size_kb: u64) -> bool {
+        self.root.join(format!("kernel/mm/hugepages/hugepages-{}kB", size_kb)).exists()
+    }
+}
+
+/// Whether a hugetlbfs is mounted at `path`
+fn hugetlbfs_mounted(path: &str) -> bool {
+    fs::read_to_string("/proc/mounts")
+    .map(|mounts| mounts.lines().any(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        fields.len() > 2 && fields[1] == path && fields[2] == "hugetlbfs"
+    }))
+    .unwrap_or(false)
+}
+
+/// Host memory as described by /proc/meminfo
+struct HostMemory {
+    root: PathBuf,
+}
+
+impl HostMemory {
+    /// Read meminfo below a procfs root (normally /proc)
+    fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn system() -> Self {
+        Self::new("/proc")
+    }
+
+    /// A meminfo value in kB, e.g. MemTotal
+    fn kb(&self, key: &str) -> Option<u64> {
+        fs::read_to_string(self.root.join("meminfo")).ok()?
+        .lines()
+        .find(|line| line.split(':').next() == Some(key))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|kb| kb.parse().ok())
+    }
+}
+
+// ============================================================================
+// QEMU COMMAND LINE
+// ============================================================================
+
+/// A QEMU option value such as `vfio-pci,host=0000:01:00.0,bus=gpu-port`
+#[derive(Clone, Default)]
+struct QemuOpts {
+    items: Vec<String>,
+}
+
+impl QemuOpts {
+    /// Options led by a type or driver name (`-device vfio-pci,...`, `-netdev user,...`)
+    fn new(kind: &str) -> Self {
+        Self { items: vec![kind.to_string()] }
+    }
+
+    /// Options that are only `key=value` pairs (`-drive file=...,format=...`)
+    fn props() -> Self {
+        Self::default()
+    }
+
+    fn set(mut self, key: &str, value: &str) -> Self {
+        // Commas inside a value are escaped by doubling them
+        self.items.push(format!("{}={}", key, value.replace(',', ",,")));
+        self
+    }
+
+    /// A bare item such as a CPU feature (`hv_time`, `+invtsc`)
+    fn flag(mut self, item: &str) -> Self {
+        self.items.push(item.to_string());
+        self
+    }
+
+    fn render(&self) -> String {
+        self.items.join(",")
+    }
+}
+
+/// Everything `start` passes to QEMU, rendered to an argv in a fixed order
+struct QemuCommand {
+    binary: String,
+    /// Host CPUs to pin to with taskset
+    taskset: Option<String>,
+    name: String,
+    machine: QemuOpts,
+    cpu: QemuOpts,
+    smp: String,
+    memory_gb: u32,
+    objects: Vec<QemuOpts>,
+    chardevs: Vec<QemuOpts>,
+    tpmdevs: Vec<QemuOpts>,
+    drives: Vec<QemuOpts>,
+    netdevs: Vec<QemuOpts>,
+    devices: Vec<QemuOpts>,
+    /// Other options in the order they were added, e.g. `-rtc ...`, `-nographic`
+    options: Vec<(String, Option<String>)>,
+    /// [qemu] extra_args, always last
+    extra: Vec<String>,
+}
+
+impl QemuCommand {
+    fn new(binary: &str) -> Self {
+        Self {
+            binary: binary.to_string(),
+            taskset: None,
+            name: String::new(),
+            machine: QemuOpts::new("q35"),
+            cpu: QemuOpts::new("host"),
+            smp: "1".to_string(),
+            memory_gb: 1,
+            objects: Vec::new(),
+            chardevs: Vec::new(),
+            tpmdevs: Vec::new(),
+            drives: Vec::new(),
+            netdevs: Vec::new(),
+            devices: Vec::new(),
+            options: Vec::new(),
+            extra: Vec::new(),
+        }
+    }
+
+    fn object(&mut self, opts: QemuOpts) {
+        self.objects.push(opts);
+    }
+
+    fn chardev(&mut self, opts: QemuOpts) {
+        self.chardevs.push(opts);
+    }
+
+    fn tpmdev(&mut self, opts: QemuOpts) {
+        self.tpmdevs.push(opts);
+    }
+
+    fn drive(&mut self, opts: QemuOpts) {
+        self.drives.push(opts);
+    }
+
+    fn netdev(&mut self, opts: QemuOpts) {
+        self.netdevs.push(opts);
+    }
+
+    fn device(&mut self, opts: QemuOpts) {
+        self.devices.push(opts);
+    }
+
+    fn option(&mut self, name: &str, value: &str) {
+        self.options.push((name.to_string(), Some(value.to_string())));
+    }
+
+    fn flag(&mut self, name: &str) {
+        self.options.push((name.to_string(), None));
+    }
+
+    /// The full argv, including the taskset wrapper
+    fn argv(&self) -> Vec<String> {
+        let mut argv = Vec::new();
+
+        if let Some(cores) = &self.taskset {
+            argv.extend(["taskset".to_string(), "-c".to_string(), cores.clone()]);
+        }
+        argv.push(self.binary.clone());
+
+        if !self.name.is_empty() {
+            argv.extend(["-name".to_string(), self.name.clone()]);
+        }
+        argv.extend([
+            "-machine".to_string(), self.machine.render(),
+            "-cpu".to_string(), self.cpu.render(),
+            "-smp".to_string(), self.smp.clone(),
+            "-m".to_string(), format!("{}G", self.memory_gb),
+            "-enable-kvm".to_string(),
+        ]);
+
+        let groups = [
+            ("-object", &self.objects),
+            ("-chardev", &self.chardevs),
+            ("-tpmdev", &self.tpmdevs),
+            ("-drive", &self.drives),
+            ("-netdev", &self.netdevs),
+            ("-device", &self.devices),
+        ];
+        for (option, values) in groups {
+            for value in values {
+                argv.push(option.to_string());
+                argv.push(value.render());
+            }
+        }
+
+        for (name, value) in &self.options {
+            argv.push(name.clone());
+            if let Some(value) = value {
+                argv.push(value.clone());
+            }
+        }
+
+        argv.extend(self.extra.iter().cloned());
+        argv
+    }
+
+    fn command(&self) -> Command {
+        let argv = self.argv();
+        let mut cmd = Command::new(&argv[0]);
+        cmd.args(&argv[1..]);
+        cmd
+    }
+
+    /// The argv as one line that can be pasted into a shell
+    fn shell_line(&self) -> String {
+        self.argv().iter().map(|a| shell_quote(a)).collect::<Vec<_>>().join(" ")
+    }
+}
+
+/// Quote an argument for POSIX sh, leaving plain words alone
+fn shell_quote(arg: &str) -> String {
+    let plain = !arg.is_empty() && arg.chars().all(|c| c.is_ascii_alphanumeric() || "-_=,.:/+@%".contains(c));
+    if plain {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+// ============================================================================
+// LIBVIRT - Optional virsh-managed backend
+// ============================================================================
+
+/// The system libvirt instance, where passthrough domains live
+const LIBVIRT_URI: &str = "qemu:///system";
+
+/// Run virsh against the system instance and return its output
+fn virsh(args: &[&str]) -> Result<String, String> {
+    let output = Command::new("virsh")
+    .arg("-c")
+    .arg(LIBVIRT_URI)
+    .args(args)
+    .output()
+    .map_err(|e| format!("Failed to run virsh (is libvirt installed?): {}", e))?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "virsh {} failed: {}",
+            args.first().unwrap_or(&""),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// Escape text for an XML attribute or element
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('\'', "&apos;")
+    .replace('"', "&quot;")
+}
+
+// ============================================================================
+// QMP - QEMU Machine Protocol client for the running VM
+// ============================================================================
+
+/// Just enough JSON for QMP replies
+#[derive(Clone)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    Str(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn parse(text: &str) -> Result<Json, String> {
+        let chars: Vec<char> = text.chars().collect();
+        let mut pos = 0;
+        let value = Json::parse_value(&chars, &mut pos)?;
+        Json::skip_whitespace(&chars, &mut pos);
+        if pos != chars.len() {
+            return Err(format!("Unexpected data after JSON value at {}", pos));
+        }
+        Ok(value)
+    }
+
+    fn skip_whitespace(chars: &[char], pos: &mut usize) {
+        while *pos < chars.len() && chars[*pos].is_whitespace() {
+            *pos += 1;
+        }
+    }
+
+    /// The four hex digits of a `\\u` escape
+    fn parse_hex4(chars: &[char], pos: &mut usize) -> Result<u32, String> {
+        let hex: String = chars.iter().skip(*pos).take(4).collect();
+        if hex.len() != 4 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("Bad \\u escape at {}", pos));
+        }
+        *pos += 4;
+        Ok(u32::from_str_radix(&hex, 16).unwrap_or(0xfffd))
+    }
+
+    fn parse_value(chars: &[char], pos: &mut usize) -> Result<Json, String> {
+        Json::skip_whitespace(chars, pos);
+        let rest: String = chars[*pos..].iter().take(5).collect();
+
+        match chars.get(*pos) {
+            Some('{') => {
+                *pos += 1;
+                let mut fields = Vec::new();
+                loop {
+                    Json::skip_whitespace(chars, pos);
+                    if chars.get(*pos) == Some(&'}') {
+                        *pos += 1;
+                        return Ok(Json::Object(fields));
+                    }
+                    let key = match Json::parse_value(chars, pos)? {
+                        Json::Str(key) => key,
+                        _ => return Err(format!("Expected an object key at {}", pos)),
+                    };
+                    Json::skip_whitespace(chars, pos);
+                    if chars.get(*pos) != Some(&':') {
+                        return Err(format!("Expected ':' at {}", pos));
+                    }
+                    *pos += 1;
+                    fields.push((key, Json::parse_value(chars, pos)?));
+                    Json::skip_whitespace(chars, pos);
+                    match chars.get(*pos) {
+                        Some(',') => *pos += 1,
+                        Some('}') => {}
+                        _ => return Err(format!("Expected ',' or '}}' at {}", pos)),
+                    }
+                }
+            }
+            Some('[') => {
+                *pos += 1;
+                let mut items = Vec::new();
+                loop {
+                    Json::skip_whitespace(chars, pos);
+                    if chars.get(*pos) == Some(&']') {
+                        *pos += 1;
+                        return Ok(Json::Array(items));
+                    }
+                    items.push(Json::parse_value(chars, pos)?);
+                    Json::skip_whitespace(chars, pos);
+                    match chars.get(*pos) {
+                        Some(',') => *pos += 1,
+                        Some(']') => {}
+                        _ => return Err(format!("Expected ',' or ']' at {}", pos)),
+                    }
+                }
+            }
+            Some('"') => {
+                *pos += 1;
+                let mut text = String::new();
+                while let Some(&c) = chars.get(*pos) {
+                    *pos += 1;
+                    match c {
+                        '"' => return Ok(Json::Str(text)),
+                        '\\' => {
+                            let escaped = chars.get(*pos).copied().ok_or("Unterminated string")?;
+                            *pos += 1;
+                            match escaped {
+                                'n' => text.push('\n'),
+                                't' => text.push('\t'),
+                                'r' => text.push('\r'),
+                                'b' => text.push('\u{8}'),
+                                'f' => text.push('\u{c}'),
+                                'u' => {
+                                    let mut code = Json::parse_hex4(chars, pos)?;
+                                    // Characters outside the BMP come as a UTF-16 surrogate pair
+                                    if (0xD800..0xDC00).contains(&code) && chars[*pos..].starts_with(&['\\', 'u']) {
+                                        let mut next = *pos + 2;
+                                        let low = Json::parse_hex4(chars, &mut next)?;
+                                        if (0xDC00..0xE000).contains(&low) {
+                                            code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+                                            *pos = next;
+                                        }
+                                    }
+                                    text.push(char::from_u32(code).unwrap_or('\u{fffd}'));
+                                }
+                                other => text.push(other),
+                            }
+                        }
+                        _ => text.push(c),
+                    }
+                }
+                Err("Unterminated string".to_string())
+            }
+            Some(c) if *c == '-' || c.is_ascii_digit() => {
+                let start = *pos;
+                while chars.get(*pos).is_some_and(|c| c.is_ascii_digit() || "+-.eE".contains(*c)) {
+                    *pos += 1;
+                }
+                let number: String = chars[start..*pos].iter().collect();
+                number.parse().map(Json::Number).map_err(|_| format!("Bad number '{}'", number))
+            }
+            _ if rest.starts_with("true") => {
+                *pos += 4;
+                Ok(Json::Bool(true))
+            }
+            _ if rest.starts_with("false") => {
+                *pos += 5;
+                Ok(Json::Bool(false))
+            }
+            _ if rest.starts_with("null") => {
+                *pos += 4;
+                Ok(Json::Null)
+            }
+            _ => Err(format!("Unexpected JSON at {}", pos)),
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn as_u64(&self) -> Option<u64> {
+        match self {
+            Json::Number(n) if *n >= 0.0 => Some(*n as u64),
+            _ => None,
+        }
+    }
+
+    fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    fn as_array(&self) -> &[Json] {
+        match self {
+            Json::Array(items) => items,
+            _ => &[],
+        }
+    }
+}
+
+/// A connection to QEMU's QMP socket, already out of capabilities negotiation
+struct Qmp {
+    reader: io::BufReader<UnixStream>,
+    writer: UnixStream,
+    /// QEMU version from the greeting, e.g. "8.2.0"
+    version: String,
+}
+
+impl Qmp {
+    fn connect(path: &Path) -> Result<Self, String> {
+        let stream = UnixStream::connect(path)
+        .map_err(|e| format!("Cannot connect to QMP socket {}: {}", path.display(), e))?;
+        stream.set_read_timeout(Some(Duration::from_secs(10))).ok();
+
+        let writer = stream.try_clone().map_err(|e| e.to_string())?;
+        let mut qmp = Qmp { reader: io::BufReader::new(stream), writer, version: String::new() };
+
+        let greeting = qmp.read_message()?;
+        let version = greeting.get("QMP")
+        .and_then(|q| q.get("version"))
+        .and_then(|v| v.get("qemu"))
+        .ok_or("Not a QMP socket (no greeting)")?;
+
+        let part = |key| version.get(key).and_then(|n| n.as_u64()).unwrap_or(0);
+        qmp.version = format!("{}.{}.{}", part("major"), part("minor"), part("micro"));
+
+        qmp.execute("qmp_capabilities", None)?;
+        Ok(qmp)
+    }
+
+    fn read_message(&mut self) -> Result<Json, String> {
+        let mut line = String::new();
+        let read = self.reader.read_line(&mut line)
+        .map_err(|e| format!("QMP read failed: {}", e))?;
+
+        if read == 0 {
+            return Err("QMP connection closed".to_string());
+        }
+
+        Json::parse(line.trim())
+    }
+
+    /// Run a command and return its `return` value. `arguments` is a JSON object.
+    fn execute(&mut self, command: &str, arguments: Option<&str>) -> Result<Json, String> {
+        let request = match arguments {
+            Some(args) => format!("{{\"execute\": \"{}\", \"arguments\": {}}}\n", command, args),
+            None => format!("{{\"execute\": \"{}\"}}\n", command),
+        };
+
+        self.writer.write_all(request.as_bytes())
+        .map_err(|e| format!("QMP write failed: {}", e))?;
+
+        // Asynchronous events can arrive before the reply; skip them
+        loop {
+            let message = self.read_message()?;
+            if let Some(value) = message.get("return") {
+                return Ok(value.clone());
+            }
+            if let Some(error) = message.get("error") {
+                let desc = error.get("desc").and_then(|d| d.as_str()).unwrap_or("unknown error");
+                return Err(format!("QMP {} failed: {}", command, desc));
+            }
+        }
+    }
+
+    /// QEMU's run state, e.g. "running", "paused", "shutdown"
+    fn run_state(&mut self) -> Result<String, String> {
+        let status = self.execute("query-status", None)?;
+        Ok(status.get("status").and_then(|s| s.as_str()).unwrap_or("unknown").to_string())
+    }
+
+    /// Whether QEMU is actually using KVM acceleration
+    fn kvm_enabled(&mut self) -> Result<bool, String> {
+        let kvm = self.execute("query-kvm", None)?;
+        Ok(kvm.get("enabled").and_then(|e| e.as_bool()).unwrap_or(false))
+    }
+
+    /// Host thread ids of QEMU's I/O threads
+    fn iothread_threads(&mut self) -> Result<Vec<u64>, String> {
+        let threads = self.execute("query-iothreads", None)?;
+        Ok(threads.as_array().iter().filter_map(|t| t.get("thread-id").and_then(|t| t.as_u64())).collect())
+    }
+
+    /// Host thread id of each vCPU, in vCPU order
+    fn vcpu_threads(&mut self) -> Result<Vec<u64>, String> {
+        let cpus = self.execute("query-cpus-fast", None)?;
+        Ok(cpus.as_array().iter().filter_map(|c| c.get("thread-id").and_then(|t| t.as_u64())).collect())
+    }
+}
+
+// ============================================================================
+// FIRMWARE - OVMF (UEFI) for the guest
+// ============================================================================
+
+/// Where distributions install OVMF: (code, vars template, Secure Boot)
+const OVMF_CANDIDATES: &[(&str, &str, bool)] = &[
+    // Fedora / Nobara (edk2-ovmf)
+    ("/usr/share/edk2/ovmf/OVMF_CODE.secboot.fd", "/usr/share/edk2/ovmf/OVMF_VARS.secboot.fd", true),
+    ("/usr/share/edk2/ovmf/OVMF_CODE.fd", "/usr/share/edk2/ovmf/OVMF_VARS.fd", false),
+    // Debian / Ubuntu (ovmf)
+    ("/usr/share/OVMF/OVMF_CODE_4M.secboot.fd", "/usr/share/OVMF/OVMF_VARS_4M.ms.fd", true),
+    ("/usr/share/OVMF/OVMF_CODE_4M.fd", "/usr/share/OVMF/OVMF_VARS_4M.fd", false),
+    ("/usr/share/OVMF/OVMF_CODE.secboot.fd", "/usr/share/OVMF/OVMF_VARS.ms.fd", true),
+    ("/usr/share/OVMF/OVMF_CODE.fd", "/usr/share/OVMF/OVMF_VARS.fd", false),
+    // Arch (edk2-ovmf)
+    ("/usr/share/edk2/x64/OVMF_CODE.secboot.4m.fd", "/usr/share/edk2/x64/OVMF_VARS.4m.fd", true),
+    ("/usr/share/edk2/x64/OVMF_CODE.4m.fd", "/usr/share/edk2/x64/OVMF_VARS.4m.fd", false),
+    // openSUSE (qemu-ovmf-x86_64)
+    ("/usr/share/qemu/ovmf-x86_64-ms-4m-code.bin", "/usr/share/qemu/ovmf-x86_64-ms-4m-vars.bin", true),
+    ("/usr/share/qemu/ovmf-x86_64-4m-code.bin", "/usr/share/qemu/ovmf-x86_64-4m-vars.bin", false),
+    // QEMU's bundled edk2-x86_64 build
+    ("/usr/share/qemu/edk2-x86_64-secure-code.fd", "/usr/share/qemu/edk2-i386-vars.fd", true),
+    ("/usr/share/qemu/edk2-x86_64-code.fd", "/usr/share/qemu/edk2-i386-vars.fd", false),
+];
+
+/// An OVMF build: the read-only code and the template for a VM's variable store
+struct Ovmf {
+    code: String,
+    vars: String,
+    secure_boot: bool,
+}
+
+impl Ovmf {
+    fn find(secure_boot: bool) -> Option<Self> {
+        OVMF_CANDIDATES.iter()
+        .find(|(code, vars, secure)| *secure == secure_boot && Path::new(code).exists() && Path::new(vars).exists())
+        .map(|(code, vars, secure)| Ovmf {
+            code: code.to_string(),
+            vars: vars.to_string(),
+            secure_boot: *secure,
+        })
+    }
+}
+
+// ============================================================================
+// PACKAGES - Distribution-specific package installation
+// ============================================================================
+
+/// Something Ten Pod needs installed, independent of what the distro calls it
+#[derive(Clone, Copy)]
+enum Requirement {
+    Qemu,
+    Ovmf,
+    Libvirt,
+    Swtpm,
+    Usbutils,
+    Pciutils,
+}
+
+impl Requirement {
+    const ALL: &'static [Requirement] = &[
+        Requirement::Qemu,
+        Requirement::Ovmf,
+        Requirement::Libvirt,
+        Requirement::Swtpm,
+        Requirement::Usbutils,
+        Requirement::Pciutils,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Requirement::Qemu => "QEMU",
+            Requirement::Ovmf => "OVMF",
+            Requirement::Libvirt => "libvirt",
+            Requirement::Swtpm => "swtpm",
+            Requirement::Usbutils => "usbutils",
+            Requirement::Pciutils => "pciutils",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum PackageManager {
+    Dnf,
+    Apt,
+    Pacman,
+    Zypper,
+}
+
+impl PackageManager {
+    /// Work out the package manager from /etc/os-release
+    fn detect() -> Option<Self> {
+        fs::read_to_string("/etc/os-release").ok()
+        .and_then(|text| Self::from_os_release(&text))
+    }
+
+    /// Match ID and ID_LIKE, so derivatives (Nobara, Pop!_OS, Manjaro) resolve to their base
+    fn from_os_release(text: &str) -> Option<Self> {
+        let mut ids = Vec::new();
+        for line in text.lines() {
+            if let Some((key, value)) = line.split_once('=') {
+                if key == "ID" || key == "ID_LIKE" {
+                    ids.extend(value.trim_matches('"').split_whitespace().map(|v| v.to_lowercase()));
+                }
+            }
+        }
+
+        ids.iter().find_map(|id| match id.as_str() {
+            "fedora" | "rhel" | "centos" | "nobara" => Some(PackageManager::Dnf),
+            "debian" | "ubuntu" => Some(PackageManager::Apt),
+            "arch" => Some(PackageManager::Pacman),
+            "suse" | "opensuse" | "opensuse-tumbleweed" | "opensuse-leap" | "sles" => Some(PackageManager::Zypper),
+            _ => None,
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            PackageManager::Dnf => "dnf",
+            PackageManager::Apt => "apt",
+            PackageManager::Pacman => "pacman",
+            PackageManager::Zypper => "zypper",
+        }
+    }
+
+    /// Distribution package names for a requirement
+    fn packages(&self, requirement: Requirement) -> &'static [&'static str] {
+        use PackageManager::*;
+        use Requirement::*;
+
+        match (self, requirement) {
+            (Dnf, Qemu) => &["qemu-kvm", "qemu-img"],
+            (Dnf, Ovmf) => &["edk2-ovmf"],
+            (Dnf, Libvirt) => &["libvirt", "libvirt-daemon-config-network", "virt-install", "virt-manager", "bridge-utils"],
+            (Dnf, Swtpm) => &["swtpm", "swtpm-tools"],
+
+            (Apt, Qemu) => &["qemu-system-x86", "qemu-utils"],
+            (Apt, Ovmf) => &["ovmf"],
+            (Apt, Libvirt) => &["libvirt-daemon-system", "libvirt-clients", "virtinst", "virt-manager", "bridge-utils"],
+            (Apt, Swtpm) => &["swtpm", "swtpm-tools"],
+
+            (Pacman, Qemu) => &["qemu-desktop"],
+            (Pacman, Ovmf) => &["edk2-ovmf"],
+            (Pacman, Libvirt) => &["libvirt", "dnsmasq", "virt-install", "virt-manager", "bridge-utils"],
+            (Pacman, Swtpm) => &["swtpm"],
+
+            (Zypper, Qemu) => &["qemu-x86", "qemu-tools"],
+            (Zypper, Ovmf) => &["qemu-ovmf-x86_64"],
+            (Zypper, Libvirt) => &["libvirt", "virt-install", "virt-manager", "bridge-utils"],
+            (Zypper, Swtpm) => &["swtpm"],
+
+            (_, Usbutils) => &["usbutils"],
+            (_, Pciutils) => &["pciutils"],
+        }
+    }
+
+    /// Full command line (without pkexec) that installs the given packages. apt's package
+    /// lists may be stale or missing (fresh containers and cloud images), so it updates them
+    /// first, in the same root shell to keep it to one password prompt.
+    fn install_command(&self, packages: &[&str]) -> Vec<String> {
+        let base: &[&str] = match self {
+            PackageManager::Dnf => &["dnf", "install", "-y"],
+            PackageManager::Apt => &["sh", "-c", r#"apt-get update && apt-get install -y "$@""#, "sh"],
+            PackageManager::Pacman => &["pacman", "-S", "--needed", "--noconfirm"],
+            PackageManager::Zypper => &["zypper", "--non-interactive", "install"],
+        };
+        base.iter().chain(packages.iter()).map(|s| s.to_string()).collect()
+    }
+
+    fn is_installed(&self, package: &str) -> bool {
+        let (program, args): (&str, &[&str]) = match self {
+            PackageManager::Dnf | PackageManager::Zypper => ("rpm", &["-q"]),
+            PackageManager::Apt => ("dpkg", &["-s"]),
+            PackageManager::Pacman => ("pacman", &["-Q"]),
+        };
+
+        Command::new(program)
+        .args(args)
+        .arg(package)
+        .output()
+        .map(|o| o.status.success())
+        .unwrap_or(false)
+    }
+
+    /// Packages for the given requirements that aren't installed yet
+    fn missing_packages(&self, requirements: &[Requirement]) -> Vec<&'static str> {
+        requirements.iter()
+        .flat_map(|r| self.packages(*r).iter().cloned())
+        .filter(|p| !self.is_installed(p))
+        .collect()
+    }
+}
+
+// ============================================================================
+// KERNEL COMMAND LINE - IOMMU arguments through whatever boot loader is in use
+// ============================================================================
+
+#[derive(Clone, Copy, PartialEq)]
+enum CpuVendor {
+    Intel,
+    Amd,
+}
+
+impl CpuVendor {
+    fn name(&self) -> &'static str {
+        match self {
+            CpuVendor::Intel => "Intel",
+            CpuVendor::Amd => "AMD",
+        }
+    }
+
+    fn iommu_arg(&self) -> &'static str {
+        match self {
+            CpuVendor::Intel => "intel_iommu=on",
+            CpuVendor::Amd => "amd_iommu=on",
+        }
+    }
+}
+
+fn cpu_vendor() -> Option<CpuVendor> {
+    let cpuinfo = fs::read_to_string("/proc/cpuinfo").ok()?;
+    let vendor = cpuinfo.lines().find(|l| l.starts_with("vendor_id"))?;
+    if vendor.contains("GenuineIntel") {
+        Some(CpuVendor::Intel)
+    } else if vendor.contains("AuthenticAMD") {
+        Some(CpuVendor::Amd)
+    } else {
+        None
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum BootLoader {
+    /// Pop!_OS
+    Kernelstub,
+    /// Fedora, RHEL (GRUB with BLS entries)
+    Grubby,
+    /// systemd-boot with /etc/kernel/cmdline and loader entries
+    SystemdBoot,
+    /// GRUB that reads /etc/default/grub.d (Debian, Ubuntu)
+    GrubDropIn,
+    /// Plain /etc/default/grub (Arch, openSUSE)
+    Grub,
+}
+
+const GRUB_DROP_IN: &str = "/etc/default/grub.d/90-tenpod.cfg";
+
+impl BootLoader {
+    fn detect() -> Option<Self> {
+        if have_program("kernelstub") {
+            Some(BootLoader::Kernelstub)
+        } else if have_program("grubby") && Path::new("/etc/default/grub").exists() {
+            Some(BootLoader::Grubby)
+        } else if Path::new("/etc/kernel/cmdline").exists()
+            || (Self::loader_entries_dir().is_some() && !Path::new("/etc/default/grub").exists()) {
+            Some(BootLoader::SystemdBoot)
+        } else if Path::new("/etc/default/grub.d").is_dir() {
+            Some(BootLoader::GrubDropIn)
+        } else if Path::new("/etc/default/grub").exists() {
+            Some(BootLoader::Grub)
+        } else {
+            None
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            BootLoader::Kernelstub => "kernelstub",
+            BootLoader::Grubby => "grubby",
+            BootLoader::SystemdBoot => "systemd-boot",
+            BootLoader::GrubDropIn => "GRUB (drop-in)",
+            BootLoader::Grub => "GRUB",
+        }
+    }
+
+    fn loader_entries_dir() -> Option<&'static str> {
+        ["/boot/loader/entries", "/efi/loader/entries", "/boot/efi/loader/entries"].iter()
+        .find(|d| Path::new(d).is_dir())
+        .cloned()
+    }
+
+    /// Arguments the next boot will use, as far as the boot loader's files (or, for tools that
+    /// manage their own, the manifest and the running kernel) tell
+    fn configured_args(&self, manifest: &Manifest) -> Vec<String> {
+        let mut args = Vec::new();
+        let grub = fs::read_to_string("/etc/default/grub").unwrap_or_default();
+
+        match self {
+            BootLoader::Grub | BootLoader::GrubDropIn => {
+                args.extend(assignment_words(&grub, "GRUB_CMDLINE_LINUX"));
+                args.extend(assignment_words(&grub, "GRUB_CMDLINE_LINUX_DEFAULT"));
+                if *self == BootLoader::GrubDropIn {
+                    let drop_in = fs::read_to_string(GRUB_DROP_IN).unwrap_or_default();
+                    args.extend(assignment_words(&drop_in, "GRUB_CMDLINE_LINUX_DEFAULT"));
+                }
+            }
+            BootLoader::SystemdBoot => args.extend(Self::kernel_cmdline_base().split_whitespace().map(String::from)),
+            BootLoader::Kernelstub | BootLoader::Grubby => {
+                let tool = self.tool_name();
+                args.extend(manifest.entries.iter().filter_map(|entry| match entry {
+                    ManifestEntry::KernelArg { tool: t, arg } if t == tool => Some(arg.clone()),
+                    _ => None,
+                }));
+                args.extend(fs::read_to_string("/proc/cmdline").unwrap_or_default().split_whitespace().map(String::from));
+            }
+        }
+
+        args
+    }
+
+    /// /etc/kernel/cmdline, or the running command line kernel-install falls back to
+    fn kernel_cmdline_base() -> String {
+        let old = fs::read_to_string("/etc/kernel/cmdline").unwrap_or_default();
+        if !old.trim().is_empty() {
+            return old.trim().to_string();
+        }
+
+        fs::read_to_string("/proc/cmdline").unwrap_or_default()
+        .split_whitespace()
+        .filter(|a| !a.starts_with("BOOT_IMAGE=") && !a.starts_with("initrd="))
+        .collect::<Vec<_>>()
+        .join(" ")
+    }
+
+    /// New contents for every file this boot loader keeps its arguments in. Each file only
+    /// gets the arguments it doesn't already have.
+    fn file_changes(&self, args: &[String]) -> Vec<(String, String, String)> {
+        let mut changes = Vec::new();
+        let new_in = |existing: &[String]| -> String {
+            args.iter().filter(|a| !existing.contains(a)).cloned().collect::<Vec<_>>().join(" ")
+        };
+
+        match self {
+            BootLoader::GrubDropIn => {
+                let old = fs::read_to_string(GRUB_DROP_IN).unwrap_or_default();
+                let mut words = assignment_words(&old, "GRUB_CMDLINE_LINUX_DEFAULT");
+                words.extend(args.iter().filter(|a| !words.contains(a)).cloned().collect::<Vec<_>>());
+                let new = format!(
+                    "# Ten Pod: IOMMU for GPU passthrough\nGRUB_CMDLINE_LINUX_DEFAULT=\"${{GRUB_CMDLINE_LINUX_DEFAULT}} {}\"\n",
+                    words.join(" ")
+                );
+                if new != old {
+                    changes.push((GRUB_DROP_IN.to_string(), old, new));
+                }
+            }
+            BootLoader::Grub => {
+                let path = "/etc/default/grub";
+                let old = fs::read_to_string(path).unwrap_or_default();
+                let added = new_in(&assignment_words(&old, "GRUB_CMDLINE_LINUX_DEFAULT"));
+                if !added.is_empty() {
+                    changes.push((path.to_string(), old.clone(), append_to_assignment(&old, "GRUB_CMDLINE_LINUX_DEFAULT", &added)));
+                }
+            }
+            BootLoader::SystemdBoot => {
+                let path = "/etc/kernel/cmdline";
+                let old = fs::read_to_string(path).unwrap_or_default();
+                let base = Self::kernel_cmdline_base();
+                let added = new_in(&base.split_whitespace().map(String::from).collect::<Vec<_>>());
+                if !added.is_empty() {
+                    changes.push((path.to_string(), old, format!("{} {}\n", base, added).trim_start().to_string()));
+                }
+
+                if let Some(dir) = Self::loader_entries_dir() {
+                    for entry in fs::read_dir(dir).into_iter().flatten().filter_map(|e| e.ok()) {
+                        let entry_path = entry.path().to_string_lossy().to_string();
+                        if !entry_path.ends_with(".conf") {
+                            continue;
+                        }
+                        if let Ok(old) = fs::read_to_string(&entry_path) {
+                            let new: Vec<String> = old.lines()
+                            .map(|l| {
+                                let added = new_in(&l.split_whitespace().skip(1).map(String::from).collect::<Vec<_>>());
+                                if l.starts_with("options") && !added.is_empty() {
+                                    format!("{} {}", l, added)
+                                } else {
+                                    l.to_string()
+                                }
+                            })
+                            .collect();
+                            let new = new.join("\n") + "\n";
+                            if new != old {
+                                changes.push((entry_path, old, new));
+                            }
+                        }
+                    }
+                }
+            }
+            BootLoader::Kernelstub | BootLoader::Grubby => {}
+        }
+
+        changes
+    }
+
+    /// Commands run (as root) to add arguments, for tools that manage their own files
+    fn arg_commands(&self, args: &[String]) -> Vec<Vec<String>> {
+        match self {
+            BootLoader::Grubby => vec![vec![
+                "grubby".to_string(),
+                "--update-kernel=ALL".to_string(),
+                format!("--args={}", args.join(" ")),
+            ]],
+            BootLoader::Kernelstub => args.iter()
+            .map(|a| vec!["kernelstub".to_string(), "-a".to_string(), a.clone()])
+            .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Show the diff (or commands) without changing anything
+    fn preview(&self, args: &[String]) {
+        for command in self.arg_commands(args) {
+            println!("Would run: pkexec {}", command.join(" "));
+        }
+        for (path, old, new) in self.file_changes(args) {
+            print_diff(&path, &old, &new);
+        }
+        if let Some(command) = self.refresh_command() {
+            println!("Would run: pkexec {}", command.join(" "));
+        }
+    }
+
+    fn apply(&self, args: &[String], manifest: &mut Manifest) -> Result<(), String> {
+        for command in self.arg_commands(args) {
+            let status = Command::new("pkexec")
+            .args(&command)
+            .status()
+            .map_err(|e| format!("Failed to run {}: {}", command[0], e))?;
+            if !status.success() {
+                return Err(format!("'{}' failed", command.join(" ")));
+            }
+        }
+
+        // Record per argument so uninstall can remove exactly what we added
+        if let BootLoader::Grubby | BootLoader::Kernelstub = self {
+            for arg in args {
+                manifest.record(ManifestEntry::KernelArg { tool: self.tool_name().to_string(), arg: arg.clone() });
+                println!("+ {}", arg);
+            }
+        }
+
+        for (path, old, new) in self.file_changes(args) {
+            print_diff(&path, &old, &new);
+            if path.contains("/loader/entries/") {
+                // Entries come and go with kernel updates, so record the arguments rather than
+                // a copy of the file that uninstall would bring back
+                write_root_file(&path, &new)?;
+                for arg in args.iter().filter(|a| !old.split_whitespace().any(|w| w == a.as_str())) {
+                    manifest.record(ManifestEntry::EntryArg { path: path.clone(), arg: arg.clone() });
+                }
+            } else {
+                manifest.write_file(&path, &new)?;
+            }
+        }
+
+        self.refresh()
+    }
+
+    fn tool_name(&self) -> &'static str {
+        match self {
+            BootLoader::Kernelstub => "kernelstub",
+            BootLoader::Grubby => "grubby",
+            _ => "file",
+        }
+    }
+
+    /// Command that regenerates the boot loader config from the files we edited
+    fn refresh_command(&self) -> Option<Vec<String>> {
+        let command: Vec<&str> = match self {
+            BootLoader::GrubDropIn | BootLoader::Grub if have_program("update-grub") => vec!["update-grub"],
+            BootLoader::GrubDropIn | BootLoader::Grub if Path::new("/boot/grub2").is_dir() => {
+                vec!["grub2-mkconfig", "-o", "/boot/grub2/grub.cfg"]
+            }
+            BootLoader::GrubDropIn | BootLoader::Grub => vec!["grub-mkconfig", "-o", "/boot/grub/grub.cfg"],
+            _ => return None,
+        };
+        Some(command.iter().map(|c| c.to_string()).collect())
+    }
+
+    fn refresh(&self) -> Result<(), String> {
+        if let Some(command) = self.refresh_command() {
+            let status = Command::new("pkexec")
+            .args(&command)
+            .status()
+            .map_err(|e| format!("Failed to run {}: {}", command[0], e))?;
+            if !status.success() {
+                return Err(format!("'{}' failed", command.join(" ")));
+            }
+        }
+
+        // Unified kernel images embed the command line and have to be rebuilt
+        if *self == BootLoader::SystemdBoot && InitramfsTool::uses_uki() {
+            if let Some(tool) = InitramfsTool::detect() {
+                tool.regenerate()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether a program is installed in one of the standard system locations
+fn have_program(program: &str) -> bool {
+    ["/usr/bin", "/usr/sbin", "/bin", "/sbin"].iter()
+    .any(|dir| Path::new(&format!("{}/{}", dir, program)).exists())
+}
+
+/// Words of a shell assignment like KEY="a b", without variable references such as ${KEY}
+fn assignment_words(contents: &str, key: &str) -> Vec<String> {
+    let prefix = format!("{}=", key);
+    contents.lines()
+    .filter(|line| line.starts_with(&prefix))
+    .flat_map(|line| line[prefix.len()..].trim().trim_matches(|c| c == '"' || c == '\'').split_whitespace())
+    .filter(|word| !word.starts_with('$'))
+    .map(String::from)
+    .collect()
+}
+
+/// Append words inside a shell assignment like KEY="a b", adding the line if it's missing
+fn append_to_assignment(contents: &str, key: &str, words: &str) -> String {
+    let prefix = format!("{}=", key);
+    let mut found = false;
+    let mut lines: Vec<String> = contents.lines()
+    .map(|line| {
+        if !found && line.starts_with(&prefix) {
+            found = true;
+            let value = line[prefix.len()..].trim().trim_matches('"');
+            if value.is_empty() {
+                format!("{}\"{}\"", prefix, words)
+            } else {
+                format!("{}\"{} {}\"", prefix, value, words)
+            }
+        } else {
+            line.to_string()
+        }
+    })
+    .collect();
+
+    if !found {
+        lines.push(format!("{}\"{}\"", prefix, words));
+    }
+    lines.join("\n") + "\n"
+}
+
+/// Minimal line diff: lines removed and added
+fn print_diff(path: &str, old: &str, new: &str) {
+    println!("--- {}", path);
+    println!("+++ {}", path);
+    for line in old.lines().filter(|l| !new.lines().any(|n| n == *l)) {
+        println!("- {}", line);
+    }
+    for line in new.lines().filter(|l| !old.lines().any(|o| o == *l)) {
+        println!("+ {}", line);
+    }
+}
+
+// ============================================================================
+// INITRAMFS - Getting vfio-pci loaded before the GPU driver
+// ============================================================================
+
+/// Modules that must be in the initramfs so vfio-pci claims the GPU first
+const VFIO_MODULES: &[&str] = &["vfio", "vfio_iommu_type1", "vfio_pci"];
+
+#[derive(Clone, Copy, PartialEq)]
+enum InitramfsTool {
+    /// Fedora, RHEL, openSUSE
+    Dracut,
+    /// Debian, Ubuntu
+    InitramfsTools,
+    /// Arch
+    Mkinitcpio,
+}
+
+/// How a generator is told to include extra modules
+enum ModuleConfig {
+    /// A file owned entirely by Ten Pod
+    DropIn { path: &'static str, contents: String },
+    /// One module name per line in a shared file
+    Lines { path: &'static str },
+}
+
+impl InitramfsTool {
+    fn detect() -> Option<Self> {
+        if have_program("mkinitcpio") {
+            Some(InitramfsTool::Mkinitcpio)
+        } else if have_program("update-initramfs") {
+            Some(InitramfsTool::InitramfsTools)
+        } else if have_program("dracut") {
+            Some(InitramfsTool::Dracut)
+        } else {
+            None
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            InitramfsTool::Dracut => "dracut",
+            InitramfsTool::InitramfsTools => "initramfs-tools",
+            InitramfsTool::Mkinitcpio => "mkinitcpio",
+        }
+    }
+
+    fn module_config(&self) -> ModuleConfig {
+        match self {
+            InitramfsTool::Dracut => ModuleConfig::DropIn {
+                path: "/etc/dracut.conf.d/10-tenpod-vfio.conf",
+                contents: format!("# Ten Pod: load vfio-pci before GPU drivers\nforce_drivers+=\" {} \"\n", VFIO_MODULES.join(" ")),
+            },
+            InitramfsTool::InitramfsTools => ModuleConfig::Lines { path: "/etc/initramfs-tools/modules" },
+            InitramfsTool::Mkinitcpio => ModuleConfig::DropIn {
+                path: "/etc/mkinitcpio.conf.d/10-tenpod-vfio.conf",
+                contents: format!("# Ten Pod: load vfio-pci before GPU drivers\nMODULES+=({})\n", VFIO_MODULES.join(" ")),
+            },
+        }
+    }
+
+    /// Unified kernel images are rebuilt through kernel-install rather than the generator directly
+    fn uses_uki() -> bool {
+        fs::read_to_string("/etc/kernel/install.conf")
+        .map(|c| c.lines().any(|l| l.replace(' ', "") == "layout=uki"))
+        .unwrap_or(false)
+    }
+
+    fn regenerate(&self) -> Result<(), String> {
+        let release = kernel_release();
+        let vmlinuz = format!("/lib/modules/{}/vmlinuz", release);
+
+        let command: Vec<&str> = if Self::uses_uki() && Path::new(&vmlinuz).exists() {
+            vec!["kernel-install", "add", &release, &vmlinuz]
+        } else {
+            match self {
+                InitramfsTool::Dracut => vec!["dracut", "--force"],
+                InitramfsTool::InitramfsTools => vec!["update-initramfs", "-u"],
+                InitramfsTool::Mkinitcpio => vec!["mkinitcpio", "-P"],
+            }
+        };
+
+        let status = Command::new("pkexec")
+        .args(&command)
+        .status()
+        .map_err(|e| format!("Failed to run {}: {}", command[0], e))?;
+
+        if !status.success() {
+            return Err(format!(
+                "Initramfs regeneration failed: '{}' exited with {}",
+                command.join(" "),
+                status.code().map(|c| c.to_string()).unwrap_or_else(|| "a signal".to_string())
+            ));
+        }
+        Ok(())
+    }
+
+    /// Check that the current kernel's initramfs actually contains vfio-pci
+    fn verify(&self) -> Result<bool, String> {
+        let release = kernel_release();
+
+        // Built into the kernel, nothing to load
+        let builtin = fs::read_to_string(format!("/lib/modules/{}/modules.builtin", release)).unwrap_or_default();
+        if builtin.lines().any(|l| l.ends_with("/vfio-pci.ko")) {
+            return Ok(true);
+        }
 
-        if !headsets.is_empty() {
-            println!("\n🎧 {} VR headset(s) ready for passthrough", headsets.len());
+        if Self::uses_uki() {
+            return Err("unified kernel image, can't list its contents".to_string());
+        }
+
+        let (program, image) = match self {
+            InitramfsTool::Dracut => ("lsinitrd", format!("/boot/initramfs-{}.img", release)),
+            InitramfsTool::InitramfsTools => ("lsinitramfs", format!("/boot/initrd.img-{}", release)),
+            InitramfsTool::Mkinitcpio => {
+                // Arch names images after the kernel package, not the release
+                let pkgbase = fs::read_to_string(format!("/lib/modules/{}/pkgbase", release))
+                .map(|p| p.trim().to_string())
+                .unwrap_or_else(|_| "linux".to_string());
+                ("lsinitcpio", format!("/boot/initramfs-{}.img", pkgbase))
+            }
+        };
+
+        if !Path::new(&image).exists() {
+            return Err(format!("{} not found", image));
+        }
+
+        // Images are root-only on some distributions
+        let output = Command::new("pkexec")
+        .args([program, &image])
+        .output()
+        .map_err(|e| format!("{} failed: {}", program, e))?;
+
+        if !output.status.success() {
+            return Err(format!("{} {} failed", program, image));
+        }
+
+        let listing = String::from_utf8_lossy(&output.stdout);
+        Ok(listing.lines().any(|l| l.contains("vfio-pci.ko") || l.contains("vfio_pci.ko")))
+    }
+}
+
+/// Release of the running kernel, as `uname -r` prints it
+fn kernel_release() -> String {
+    fs::read_to_string("/proc/sys/kernel/osrelease")
+    .map(|r| r.trim().to_string())
+    .unwrap_or_default()
+}
+
+// ============================================================================
+// CONFIGURATION - /etc/tenpod/tenpod.conf, then ~/.config/tenpod/tenpod.conf
+// ============================================================================
+
+const SYSTEM_CONFIG_PATH: &str = "/etc/tenpod/tenpod.conf";
+
+/// VM settings, loaded from the config files and overridden from the command line
+struct Config {
+    name: String,
+    /// "qemu" runs qemu-system-x86_64 directly, "libvirt" defines and starts a libvirt domain
+    backend: String,
+    /// Seconds Windows gets to shut down after `stop` presses the power button
+    shutdown_timeout: u64,
+    memory_gb: u32,
+    /// Host CPUs for the vCPUs, or "auto" to pick whole cores from one cache domain
+    cpu_cores: String,
+    /// Host CPUs for QEMU's emulator and I/O threads, "auto" for every CPU the vCPUs don't use
+    emulator_cores: String,
+    sockets: u32,
+    cores: u32,
+    threads: u32,
+    disk_path: String,
+    iso_path: String,
+    gpu: Option<String>,
+    /// "uefi" (OVMF) or "bios" (SeaBIOS, for guests installed before UEFI support)
+    firmware: String,
+    secure_boot: bool,
+    /// Override the detected OVMF firmware files
+    ovmf_code: Option<String>,
+    ovmf_vars: Option<String>,
+    /// Attach a software TPM 2.0 (swtpm), required by Windows 11
+    tpm: bool,
+    /// Hugepages for guest memory: auto (fall back to normal memory), required or off
+    hugepages: String,
+    /// Hugepage size: 2M or 1G
+    hugepage_size: String,
+    /// NUMA node for guest memory and vCPUs: auto (the GPU's node), off, or a node number
+    numa_node: String,
+    /// Keep host tasks off the vCPU cores while the VM runs (cgroup AllowedCPUs)
+    isolate_host: bool,
+    /// Also add isolcpus/nohz_full/rcu_nocbs for the vCPU cores to the kernel command line
+    isolate_kernel_args: bool,
+    /// Use the vendor-reset module for AMD GPUs with the reset bug
+    vendor_reset: bool,
+    /// Bind the GPU to vfio-pci when the VM starts and give it back when it exits
+    auto_bind: bool,
+    /// Memory (GB) that must stay with the host
+    host_memory_gb: u32,
+    /// Logical CPUs that must stay with the host
+    host_cpus: u32,
+    extra_args: Vec<String>,
+    /// Files that were actually read, in load order
+    sources: Vec<String>,
+    /// The config options given on the command line, as given
+    cli_options: Vec<String>,
+}
+
+impl Config {
+    fn new() -> Self {
+        Self {
+            name: "TenPod".to_string(),
+            backend: "qemu".to_string(),
+            shutdown_timeout: 60,
+            memory_gb: 16, // Recommended for VR
+            cpu_cores: "auto".to_string(),
+            emulator_cores: "auto".to_string(),
+            sockets: 1,
+            cores: 4,
+            threads: 1,
+            disk_path: "/var/lib/libvirt/images/win10_tenpod.qcow2".to_string(),
+            iso_path: "/var/lib/libvirt/images/win10.iso".to_string(),
+            gpu: None,
+            firmware: "auto".to_string(),
+            secure_boot: false,
+            ovmf_code: None,
+            ovmf_vars: None,
+            tpm: true,
+            hugepages: "auto".to_string(),
+            hugepage_size: "2M".to_string(),
+            numa_node: "auto".to_string(),
+            isolate_host: false,
+            isolate_kernel_args: false,
+            vendor_reset: false,
+            auto_bind: false,
+            host_memory_gb: 4,
+            host_cpus: 2,
+            extra_args: Vec::new(),
+            sources: Vec::new(),
+            cli_options: Vec::new(),
+        }
+    }
+
+    /// Per-user config file ($XDG_CONFIG_HOME/tenpod/tenpod.conf)
+    fn user_path() -> Option<String> {
+        std::env::var("XDG_CONFIG_HOME").ok()
+        .filter(|d| !d.is_empty())
+        .or_else(|| std::env::var("HOME").ok().map(|h| format!("{}/.config", h)))
+        .map(|d| format!("{}/tenpod/tenpod.conf", d))
+    }
+
+    /// Defaults, then the system file, the user file, `--config <file>`, and finally
+    /// individual command line overrides. Consumed options are removed from `args`.
+    fn load(args: &mut Vec<String>) -> Result<Self, String> {
+        let mut config = Self::new();
+
+        for path in [Some(SYSTEM_CONFIG_PATH.to_string()), Self::user_path()].iter().flatten() {
+            if Path::new(path).exists() {
+                config.load_file(path)?;
+            }
+        }
+
+        let mut overrides = Vec::new();
+        let mut i = 0;
+        while i < args.len() {
+            let key = match args[i].as_str() {
+                "--config" => "config",
+                "--memory" => "vm.memory_gb",
+                "--cpu-cores" => "vm.cpu_cores",
+                "--gpu" => "gpu.address",
+                "--disk" => "storage.disk",
+                "--iso" => "storage.iso",
+                "--set" => "",
+                _ => {
+                    i += 1;
+                    continue;
+                }
+            };
+
+            let value = args.get(i + 1).cloned()
+            .ok_or_else(|| format!("{} requires a value", args[i]))?;
+            config.cli_options.extend(args.drain(i..i + 2));
+
+            if key == "config" {
+                config.load_file(&value)?;
+            } else if key.is_empty() {
+                let (k, v) = value.split_once('=')
+                .ok_or_else(|| format!("--set expects section.key=value, got '{}'", value))?;
+                overrides.push((k.trim().to_string(), v.trim().to_string()));
+            } else {
+                overrides.push((key.to_string(), value));
+            }
+        }
+
+        for (key, value) in overrides {
+            let (section, name) = key.split_once('.').unwrap_or(("", &key));
+            config.set(section, name, &value)
+            .map_err(|e| format!("command line: {}", e))?;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Parse an INI-style file: `[section]` headers, `key = value` lines, `#` comments
+    fn load_file(&mut self, path: &str) -> Result<(), String> {
+        let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Cannot read config {}: {}", path, e))?;
+
+        let mut section = String::new();
+        for (n, raw) in contents.lines().enumerate() {
+            let line = raw.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+
+            if line.starts_with('[') && line.ends_with(']') {
+                section = line[1..line.len() - 1].trim().to_string();
+                continue;
+            }
+
+            let (key, value) = line.split_once('=')
+            .ok_or_else(|| format!("{}:{}: expected 'key = value', got '{}'", path, n + 1, line))?;
+
+            self.set(&section, key.trim(), strip_comment(value).trim_matches('"'))
+            .map_err(|e| format!("{}:{}: {}", path, n + 1, e))?;
+        }
+
+        self.sources.push(path.to_string());
+        Ok(())
+    }
+
+    fn set(&mut self, section: &str, key: &str, value: &str) -> Result<(), String> {
+        let number = |v: &str| v.parse::<u32>()
+        .map_err(|_| format!("{}.{} must be a whole number, got '{}'", section, key, v));
+        let boolean = |v: &str| match v {
+            "true" | "yes" | "on" | "1" => Ok(true),
+            "false" | "no" | "off" | "0" => Ok(false),
+            _ => Err(format!("{}.{} must be true or false, got '{}'", section, key, v)),
+        };
+
+        match (section, key) {
+            ("vm", "name") => self.name = value.to_string(),
+            ("vm", "backend") => self.backend = value.to_lowercase(),
+            ("vm", "shutdown_timeout") => self.shutdown_timeout = number(value)? as u64,
+            ("vm", "memory_gb") => self.memory_gb = number(value)?,
+            ("vm", "cpu_cores") => self.cpu_cores = value.to_string(),
+            ("vm", "emulator_cores") => self.emulator_cores = value.to_string(),
+            ("vm", "sockets") => self.sockets = number(value)?,
+            ("vm", "cores") => self.cores = number(value)?,
+            ("vm", "threads") => self.threads = number(value)?,
+            ("storage", "disk") => self.disk_path = value.to_string(),
+            ("storage", "iso") => self.iso_path = value.to_string(),
+            ("gpu", "address") => {
+                self.gpu = if value.is_empty() { None } else { Some(normalize_pci_address(value)) }
+            }
+            ("firmware", "type") => self.firmware = value.to_lowercase(),
+            ("firmware", "secure_boot") => self.secure_boot = boolean(value)?,
+            ("firmware", "ovmf_code") => self.ovmf_code = Some(value.to_string()).filter(|v| !v.is_empty()),
+            ("firmware", "ovmf_vars") => self.ovmf_vars = Some(value.to_string()).filter(|v| !v.is_empty()),
+            ("tpm", "enabled") => self.tpm = boolean(value)?,
+            ("hugepages", "mode") => self.hugepages = value.to_string(),
+            ("hugepages", "size") => self.hugepage_size = value.to_uppercase(),
+            ("vm", "numa_node") => self.numa_node = value.to_string(),
+            ("isolation", "runtime") => self.isolate_host = boolean(value)?,
+            ("isolation", "kernel_args") => self.isolate_kernel_args = boolean(value)?,
+            ("gpu", "vendor_reset") => self.vendor_reset = boolean(value)?,
+            ("gpu", "auto_bind") => self.auto_bind = boolean(value)?,
+            ("host", "memory_gb") => self.host_memory_gb = number(value)?,
+            ("host", "cpus") => self.host_cpus = number(value)?,
+            ("qemu", "extra_args") => {
+                self.extra_args.extend(value.split_whitespace().map(|a| a.to_string()))
+            }
+            _ => return Err(format!("unknown setting '{}' in [{}]", key, section)),
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.name.is_empty() || !self.name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err(format!("Invalid config: vm.name '{}' may only use letters, digits, - and _", self.name));
+        }
+
+        if self.backend != "qemu" && self.backend != "libvirt" {
+            return Err(format!("Invalid config: vm.backend must be qemu or libvirt, got '{}'", self.backend));
+        }
+
+        if !["auto", "uefi", "bios"].contains(&self.firmware.as_str()) {
+            return Err(format!("Invalid config: firmware.type must be auto, uefi or bios, got '{}'", self.firmware));
+        }
+
+        if self.secure_boot && self.firmware == "bios" {
+            return Err("Invalid config: firmware.secure_boot needs firmware.type = uefi".to_string());
+        }
+
+        if self.ovmf_code.is_some() != self.ovmf_vars.is_some() {
+            return Err("Invalid config: set both firmware.ovmf_code and firmware.ovmf_vars, or neither".to_string());
+        }
+
+        if self.memory_gb == 0 {
+            return Err("Invalid config: vm.memory_gb must be at least 1".to_string());
+        }
+
+        if !["auto", "required", "off"].contains(&self.hugepages.as_str()) {
+            return Err(format!("Invalid config: hugepages.mode must be auto, required or off, got '{}'", self.hugepages));
+        }
+
+        if self.hugepage_size != "2M" && self.hugepage_size != "1G" {
+            return Err(format!("Invalid config: hugepages.size must be 2M or 1G, got '{}'", self.hugepage_size));
+        }
+
+        // libvirt only uses the hugetlbfs mounts listed in qemu.conf, /dev/hugepages by default
+        if self.hugepage_size == "1G" && self.hugepages != "off" && self.backend == "libvirt" {
+            return Err("Invalid config: hugepages.size = 1G needs backend = qemu (libvirt only uses the 2M mount)".to_string());
+        }
+
+        if self.numa_node != "auto" && self.numa_node != "off" && self.numa_node.parse::<u32>().is_err() {
+            return Err(format!("Invalid config: vm.numa_node must be auto, off or a node number, got '{}'", self.numa_node));
+        }
+
+        if self.host_cpus == 0 {
+            return Err("Invalid config: host.cpus must be at least 1".to_string());
+        }
+
+        if self.sockets == 0 || self.cores == 0 || self.threads == 0 {
+            return Err("Invalid config: vm.sockets, vm.cores and vm.threads must all be at least 1".to_string());
+        }
+
+        if self.emulator_cores != "auto" {
+            parse_cpu_list(&self.emulator_cores)
+            .map_err(|e| format!("Invalid config: vm.emulator_cores: {}", e))?;
+        }
+
+        let pinned = if self.cpu_cores == "auto" {
+            Vec::new()
+        } else {
+            parse_cpu_list(&self.cpu_cores)
+            .map_err(|e| format!("Invalid config: vm.cpu_cores: {}", e))?
+        };
+
+        let vcpus = self.vcpus() as usize;
+        if self.cpu_cores != "auto" && vcpus > pinned.len() {
+            return Err(format!(
+                "Invalid config: {} vCPUs ({} sockets x {} cores x {} threads) but only {} host CPUs in vm.cpu_cores = {}",
+                vcpus, self.sockets, self.cores, self.threads, pinned.len(), self.cpu_cores
+            ));
+        }
+
+        if let Some(gpu) = &self.gpu {
+            let valid = gpu.len() == 12 && gpu.chars().enumerate().all(|(i, c)| match i {
+                4 | 7 => c == ':',
+                10 => c == '.',
+                _ => c.is_ascii_hexdigit(),
+            });
+            if !valid {
+                return Err(format!("Invalid config: gpu.address '{}' is not a PCI address like 0000:01:00.0", gpu));
+            }
+        }
+
+        if self.disk_path.is_empty() {
+            return Err("Invalid config: storage.disk must not be empty".to_string());
+        }
+
+        Ok(())
+    }
+
+    fn vcpus(&self) -> u32 {
+        self.sockets * self.cores * self.threads
+    }
+
+    /// Hugepage size in kB
+    fn hugepage_kb(&self) -> u64 {
+        if self.hugepage_size == "1G" { HUGEPAGE_1G_KB } else { HUGEPAGE_2M_KB }
+    }
+
+    /// Effective configuration in config-file syntax
+    fn render(&self) -> String {
+        format!(
+            "[vm]\nname = {}\nbackend = {}\nshutdown_timeout = {}\nmemory_gb = {}\ncpu_cores = {}\nemulator_cores = {}\nnuma_node = {}\nsockets = {}\ncores = {}\nthreads = {}\n\n\
+[storage]\ndisk = {}\niso = {}\n\n\
+[firmware]\ntype = {}\nsecure_boot = {}\novmf_code = {}\novmf_vars = {}\n\n\
+[tpm]\nenabled = {}\n\n\
+[hugepages]\nmode = {}\nsize = {}\n\n\
+[isolation]\nruntime = {}\nkernel_args = {}\n\n\
+[gpu]\naddress = {}\nvendor_reset = {}\nauto_bind = {}\n\n\
+[host]\nmemory_gb = {}\ncpus = {}\n\n\
+[qemu]\nextra_args = {}\n",
+            self.name, self.backend, self.shutdown_timeout, self.memory_gb, self.cpu_cores, self.emulator_cores, self.numa_node, self.sockets, self.cores, self.threads,
+            self.disk_path, self.iso_path,
+            self.firmware, self.secure_boot,
+            self.ovmf_code.as_deref().unwrap_or(""),
+            self.ovmf_vars.as_deref().unwrap_or(""),
+            self.tpm,
+            self.hugepages, self.hugepage_size,
+            self.isolate_host, self.isolate_kernel_args,
+            self.gpu.as_deref().unwrap_or(""),
+            self.vendor_reset,
+            self.auto_bind,
+            self.host_memory_gb, self.host_cpus,
+            self.extra_args.join(" ")
+        )
+    }
+}
+
+/// A config value without its trailing ` # comment` or ` ; comment`. A `#` or `;` inside double
+/// quotes, or not preceded by whitespace (e.g. in a path), is part of the value.
+fn strip_comment(value: &str) -> &str {
+    let mut quoted = false;
+    let mut previous = ' ';
+    for (i, c) in value.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' | ';' if !quoted && previous.is_whitespace() => return value[..i].trim(),
+            _ => {}
+        }
+        previous = c;
+    }
+    value.trim()
+}
+
+/// Parse a Linux CPU list such as "4-7" or "0,2,4-6"
+fn parse_cpu_list(list: &str) -> Result<Vec<u32>, String> {
+    let mut cpus = Vec::new();
+    for part in list.trim().split(',').filter(|p| !p.is_empty()) {
+        let bad = || format!("'{}' is not a CPU list like 4-7 or 2,3,6,7", list);
+        match part.split_once('-') {
+            Some((a, b)) => {
+                let a: u32 = a.trim().parse().map_err(|_| bad())?;
+                let b: u32 = b.trim().parse().map_err(|_| bad())?;
+                if a > b {
+                    return Err(bad());
+                }
+                cpus.extend(a..=b);
+            }
+            None => cpus.push(part.trim().parse().map_err(|_| bad())?),
+        }
+    }
+
+    if cpus.is_empty() {
+        return Err("CPU list is empty".to_string());
+    }
+    Ok(cpus)
+}
+
+/// Accept "01:00.0" as well as "0000:01:00.0"
+fn normalize_pci_address(addr: &str) -> String {
+    let addr = addr.trim().to_lowercase();
+    if addr.matches(':').count() == 1 {
+        format!("0000:{}", addr)
+    } else {
+        addr
+    }
+}
+
+// ============================================================================
+// INSTALL MANIFEST - Record of every system change Ten Pod made
+// ============================================================================
+
+const STATE_DIR: &str = "/var/lib/tenpod";
+const MANIFEST_PATH: &str = "/var/lib/tenpod/manifest";
+const BACKUP_DIR: &str = "/var/lib/tenpod/backup";
+
+/// A single system change made by the installer
+#[derive(Clone, PartialEq)]
+enum ManifestEntry {
+    /// File written by Ten Pod. `prior` is the checksum of the file it replaced
+    /// (a copy is kept in the backup directory), or None if it didn't exist.
+    File { path: String, checksum: String, prior: Option<String> },
+    /// Line appended to a file Ten Pod doesn't own
+    Line { path: String, line: String },
+    /// Supplementary group added to a user
+    Group { user: String, group: String },
+    /// Package that wasn't installed before Ten Pod installed it
+    Package { name: String },
+    /// VM disk image created by Ten Pod
+    Disk { path: String },
+    /// Kernel argument added through a boot loader tool (grubby, kernelstub)
+    KernelArg { tool: String, arg: String },
+    /// Kernel argument added to the options of a systemd-boot loader entry
+    EntryArg { path: String, arg: String },
+}
+
+impl ManifestEntry {
+    /// Tab-separated line as stored in the manifest
+    fn to_line(&self) -> String {
+        match self {
+            ManifestEntry::File { path, checksum, prior } => {
+                format!("file\t{}\t{}\t{}", path, checksum, prior.as_deref().unwrap_or("-"))
+            }
+            ManifestEntry::Line { path, line } => format!("line\t{}\t{}", path, line),
+            ManifestEntry::Group { user, group } => format!("group\t{}\t{}", user, group),
+            ManifestEntry::Package { name } => format!("package\t{}", name),
+            ManifestEntry::Disk { path } => format!("disk\t{}", path),
+            ManifestEntry::KernelArg { tool, arg } => format!("kernelarg\t{}\t{}", tool, arg),
+            ManifestEntry::EntryArg { path, arg } => format!("entryarg\t{}\t{}", path, arg),
+        }
+    }
+
+    fn from_line(line: &str) -> Option<Self> {
+        let fields: Vec<&str> = line.split('\t').collect();
+        match fields.as_slice() {
+            ["file", path, checksum, prior] => Some(ManifestEntry::File {
+                path: path.to_string(),
+                checksum: checksum.to_string(),
+                prior: if *prior == "-" { None } else { Some(prior.to_string()) },
+            }),
+            ["line", path, line] => Some(ManifestEntry::Line { path: path.to_string(), line: line.to_string() }),
+            ["group", user, group] => Some(ManifestEntry::Group { user: user.to_string(), group: group.to_string() }),
+            ["package", name] => Some(ManifestEntry::Package { name: name.to_string() }),
+            ["disk", path] => Some(ManifestEntry::Disk { path: path.to_string() }),
+            ["kernelarg", tool, arg] => Some(ManifestEntry::KernelArg { tool: tool.to_string(), arg: arg.to_string() }),
+            ["entryarg", path, arg] => Some(ManifestEntry::EntryArg { path: path.to_string(), arg: arg.to_string() }),
+            _ => None,
+        }
+    }
+}
+
+/// Machine-readable list of everything `install` changed, stored in /var/lib/tenpod/manifest
+struct Manifest {
+    entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    /// Load the manifest, or start an empty one if Ten Pod was never installed
+    fn load() -> Self {
+        let entries = fs::read_to_string(MANIFEST_PATH)
+        .map(|s| {
+            s.lines()
+            .filter(|l| !l.starts_with('#') && !l.trim().is_empty())
+            .filter_map(ManifestEntry::from_line)
+            .collect()
+        })
+        .unwrap_or_default();
+
+        Self { entries }
+    }
+
+    fn exists() -> bool {
+        Path::new(MANIFEST_PATH).exists()
+    }
+
+    fn save(&self) -> Result<(), String> {
+        let mut contents = String::from("# Ten Pod install manifest v1\n");
+        for entry in &self.entries {
+            contents.push_str(&entry.to_line());
+            contents.push('\n');
+        }
+        write_root_file(MANIFEST_PATH, &contents)
+    }
+
+    /// Add an entry, ignoring exact duplicates from a repeated install
+    fn record(&mut self, entry: ManifestEntry) {
+        if !self.entries.contains(&entry) {
+            self.entries.push(entry);
+        }
+    }
+
+    /// Entry for a file Ten Pod wrote, if any
+    fn file_entry(&self, path: &str) -> Option<&ManifestEntry> {
+        self.entries.iter().find(|e| matches!(e, ManifestEntry::File { path: p, .. } if p == path))
+    }
+
+    /// Whether any recorded change affects the kernel command line
+    fn touches_boot_config(&self) -> bool {
+        self.entries.iter().any(|e| match e {
+            ManifestEntry::KernelArg { .. } | ManifestEntry::EntryArg { .. } => true,
+            ManifestEntry::File { path, .. } => {
+                path.starts_with("/etc/default/grub") || path == "/etc/kernel/cmdline" || path.contains("/loader/entries/")
+            }
+            _ => false,
+        })
+    }
+
+    /// Undo a single recorded change
+    fn revert(&self, entry: &ManifestEntry) -> Result<(), String> {
+        let status = match entry {
+            ManifestEntry::File { path, prior: Some(sum), .. } => {
+                Command::new("pkexec")
+                .args(["install", "-m", "644", &backup_path(sum), path])
+                .status()
+            }
+            ManifestEntry::File { path, prior: None, .. } => {
+                Command::new("pkexec").args(["rm", "-f", path]).status()
+            }
+            ManifestEntry::Line { path, line } => {
+                let contents = fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read {}: {}", path, e))?;
+                let kept: Vec<&str> = contents.lines().filter(|l| l != line).collect();
+                let mut restored = kept.join("\n");
+                restored.push('\n');
+                return write_root_file(path, &restored);
+            }
+            ManifestEntry::Group { user, group } => {
+                Command::new("pkexec").args(["gpasswd", "-d", user, group]).status()
+            }
+            ManifestEntry::KernelArg { tool, arg } if tool == "grubby" => {
+                Command::new("pkexec")
+                .args(["grubby", "--update-kernel=ALL", &format!("--remove-args={}", arg)])
+                .status()
+            }
+            ManifestEntry::KernelArg { tool, arg } if tool == "kernelstub" => {
+                Command::new("pkexec").args(["kernelstub", "-d", arg]).status()
+            }
+            ManifestEntry::KernelArg { .. } => return Ok(()),
+            ManifestEntry::EntryArg { path, arg } => {
+                // Kernel updates remove old entries; one that's gone has nothing left to revert
+                let contents = match fs::read_to_string(path) {
+                    Ok(contents) => contents,
+                    Err(_) => return Ok(()),
+                };
+                let restored = remove_entry_option(&contents, arg);
+                if restored == contents {
+                    return Ok(());
+                }
+                return write_root_file(path, &restored);
+            }
+            ManifestEntry::Package { .. } | ManifestEntry::Disk { .. } => return Ok(()),
+        };
+
+        match status {
+            Ok(s) if s.success() => Ok(()),
+            Ok(_) => Err(format!("Failed to revert: {}", entry.to_line())),
+            Err(e) => Err(format!("Failed to revert {}: {}", entry.to_line(), e)),
+        }
+    }
+
+    /// Write a system file, backing up whatever was there the first time Ten Pod touches it
+    fn write_file(&mut self, path: &str, contents: &str) -> Result<(), String> {
+        let prior = match self.file_entry(path) {
+            Some(ManifestEntry::File { prior, .. }) => prior.clone(),
+            _ => match fs::read(path) {
+                Ok(old) => {
+                    let sum = checksum(&old);
+                    let status = Command::new("pkexec")
+                    .args(["install", "-D", "-m", "644", path, &backup_path(&sum)])
+                    .status()
+                    .map_err(|e| format!("backup of {} failed: {}", path, e))?;
+                    if !status.success() {
+                        return Err(format!("backup of {} failed", path));
+                    }
+                    Some(sum)
+                }
+                Err(_) => None,
+            },
+        };
+
+        write_root_file(path, contents)?;
+
+        self.entries.retain(|e| !matches!(e, ManifestEntry::File { path: p, .. } if p == path));
+        self.entries.push(ManifestEntry::File {
+            path: path.to_string(),
+            checksum: checksum(contents.as_bytes()),
+            prior,
+        });
+        Ok(())
+    }
+
+    /// Append a line to a system file unless it's already there
+    fn append_line(&mut self, path: &str, line: &str) -> Result<(), String> {
+        // A line the user already had isn't ours to remove on uninstall
+        let existing = fs::read_to_string(path).unwrap_or_default();
+        if existing.lines().any(|l| l == line) {
+            return Ok(());
+        }
+
+        // The line goes through stdin, so nothing in it or the path reaches a shell
+        let mut child = Command::new("pkexec")
+        .args(["tee", "-a", path])
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::null())
+        .spawn()
+        .map_err(|e| format!("Failed to update {}: {}", path, e))?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            let separator = if existing.is_empty() || existing.ends_with('\n') { "" } else { "\n" };
+            stdin.write_all(format!("{}{}\n", separator, line).as_bytes())
+            .map_err(|e| format!("Failed to update {}: {}", path, e))?;
+        }
+
+        let status = child.wait().map_err(|e| format!("Failed to update {}: {}", path, e))?;
+        if !status.success() {
+            return Err(format!("Failed to update {}", path));
+        }
+
+        self.record(ManifestEntry::Line { path: path.to_string(), line: line.to_string() });
+        Ok(())
+    }
+}
+
+/// Loader entry with an argument taken out of its options lines
+fn remove_entry_option(contents: &str, arg: &str) -> String {
+    let lines: Vec<String> = contents.lines()
+    .map(|l| {
+        if l.starts_with("options") && l.split_whitespace().skip(1).any(|w| w == arg) {
+            l.split_whitespace().filter(|w| *w != arg).collect::<Vec<_>>().join(" ")
+        } else {
+            l.to_string()
         }
+    })
+    .collect();
+    lines.join("\n") + "\n"
+}
+
+/// FNV-1a 64-bit checksum, enough to tell whether a file was changed since we wrote it
+fn checksum(data: &[u8]) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in data {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x}", hash)
+}
+
+/// Where the pre-install copy of a file with the given checksum is kept
+fn backup_path(sum: &str) -> String {
+    format!("{}/{}", BACKUP_DIR, sum)
+}
+
+/// Write a root-owned file through pkexec, creating parent directories as needed
+fn write_root_file(path: &str, contents: &str) -> Result<(), String> {
+    let parent = Path::new(path).parent().and_then(|p| p.to_str()).unwrap_or("/");
+
+    let mut child = Command::new("pkexec")
+    .arg("sh")
+    .arg("-c")
+    .arg(r#"mkdir -p "$1" && cat > "$2""#)
+    .args(["sh", parent, path])
+    .stdin(std::process::Stdio::piped())
+    .stdout(std::process::Stdio::null())
+    .spawn()
+    .map_err(|e| format!("Failed to write {}: {}", path, e))?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(contents.as_bytes())
+        .map_err(|e| format!("Failed to write {}: {}", path, e))?;
     }
+
+    let status = child.wait().map_err(|e| format!("Failed to write {}: {}", path, e))?;
+    if !status.success() {
+        return Err(format!("Failed to write {} (authentication cancelled?)", path));
+    }
+    Ok(())
 }
 
 fn print_help() {
@@ -535,98 +5442,1196 @@ fn print_help() {
 
     COMMANDS:
     install     One-click system setup (run once, requires reboot)
+                (add --dry-run to print the package command without running it)
     start       Launch Windows VM with GPU & headset passthrough
-    stop        Stop the running VM
+                (--dry-run or --print-cmd prints the QEMU command instead,
+                --detach runs it in the background and logs to a file)
+    stop        Shut the VM down (ACPI power button, then SIGTERM/SIGKILL after
+                vm.shutdown_timeout; --force skips the clean shutdown)
+    logs        Show the log of the last background start (-f to follow)
+    service     install|uninstall a systemd unit for the VM (user unit, or
+                --system for /etc/systemd/system; --dry-run prints it)
+    pause       Pause the running VM
+    resume      Resume a paused VM
+    reset       Hard-reset the running VM
     status      Show detected hardware and system status
+    uninstall   Revert system setup and return the GPU to Linux
+                (add --remove-disk to also delete the VM disk)
+    kernel-args Add intel_iommu=on/amd_iommu=on iommu=pt to the boot loader
+                (--vfio-ids also adds vfio-pci.ids=, --isolate adds isolcpus/nohz_full/
+                rcu_nocbs for the vCPU cores, --dry-run shows the diff only)
+    bind        Move the GPU to vfio-pci now (no reboot needed)
+    unbind      Give the GPU back to its Linux driver
+    export-xml  Write the VM as a libvirt domain XML ([file], default <name>.xml)
+    topology    Show host cores, SMT siblings and caches, and the CPUs the VM gets
+    config      Print the effective configuration
     help        Show this help message
 
+    OPTIONS (override the config file):
+    --config <file>         Load an additional config file
+    --memory <GB>           Guest memory
+    --cpu-cores <list>      Host CPUs for the vCPUs (e.g. 4-7, or auto)
+    --gpu <address>         GPU to pass through (e.g. 0000:01:00.0)
+    --force                 Allow passing through the GPU driving the host display
+    --disk <path>           VM disk image
+    --iso <path>            Installer ISO
+    --set <section.key=value>  Any other setting, e.g. --set vm.cores=6
+
+    CONFIGURATION:
+    /etc/tenpod/tenpod.conf, then ~/.config/tenpod/tenpod.conf:
+        [vm]
+        name = TenPod
+        backend = qemu            # or libvirt (virt-manager, libvirt hooks)
+        shutdown_timeout = 60     # seconds before stop escalates to SIGTERM/SIGKILL
+        memory_gb = 16
+        cpu_cores = auto          # or a host CPU list like 4-7,12-15
+        emulator_cores = auto     # CPUs for QEMU's own threads
+        numa_node = auto          # memory and vCPUs on the GPU's NUMA node (auto | off | N)
+        sockets = 1
+        cores = 4
+        threads = 1
+        [storage]
+        disk = /var/lib/libvirt/images/win10_tenpod.qcow2
+        iso = /var/lib/libvirt/images/win10.iso
+        [firmware]
+        type = auto               # uefi, or bios for disks installed before Ten Pod used UEFI
+        secure_boot = false
+        [tpm]
+        enabled = true            # software TPM 2.0 (swtpm) for Windows 11
+        [hugepages]
+        mode = auto               # allocated at start, freed on exit (auto | required | off)
+        size = 2M                 # 2M or 1G pages
+        [isolation]
+        runtime = false           # move host tasks off the vCPU cores while the VM runs
+        kernel_args = false       # isolcpus/nohz_full/rcu_nocbs for them (kernel-args, install)
+        [gpu]
+        address = 0000:01:00.0
+        vendor_reset = false      # AMD reset bug workaround
+        auto_bind = false         # bind to vfio-pci at start, unbind at exit
+        [host]
+        memory_gb = 4             # RAM the VM must leave to the host
+        cpus = 2                  # CPUs the VM must leave to the host
+        [qemu]
+        extra_args = -device usb-tablet
+
     FIRST-TIME SETUP:
     1. ./tenpod.rs install     # Configure system (one-time)
     2. sudo reboot             # Apply VFIO changes
-    3. Download Windows 10 ISO to /var/lib/libvirt/images/win10.iso
+    3. Download the Windows 11 ISO to /var/lib/libvirt/images/win10.iso
     4. Plug in VR headset
     5. ./tenpod.rs start       # Launch VM
 
     FEATURES:
-    ✓ Automatic GPU + Audio passthrough
+    ✓ Automatic GPU passthrough with all its functions (Audio, USB-C)
     ✓ VR headset auto-detection (Index, Quest, Reverb G2, etc.)
     ✓ Optimized for 90Hz+ VR (low latency, stable frame times)
     ✓ Hyper-V enlightenments for Windows performance
+    ✓ UEFI (OVMF) boot with optional Secure Boot
+    ✓ Software TPM 2.0 (swtpm) for Windows 11 and anti-cheat
     ✓ Hugepages for memory performance
-    ✓ Works with any NVIDIA driver (nvidia, nouveau, or none)
+    ✓ Works with NVIDIA, AMD and Intel Arc GPUs, whatever driver they use
 
     REQUIREMENTS:
-    • NVIDIA GPU (GTX 900+ or RTX series)
+    • NVIDIA (GTX 900+ / RTX), AMD Radeon or Intel Arc GPU
     • CPU with VT-x/AMD-V enabled in BIOS
     • IOMMU enabled in BIOS
     • At least 16GB RAM (8GB for VM, 8GB for host)
-    • Nobara Linux or Fedora-based distro
+    • Fedora/Nobara, Debian/Ubuntu, Arch or openSUSE
     "#);
 }
 
-fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    let command = args.get(1).map(|s| s.as_str()).unwrap_or("help");
+fn main() {
+    let mut args: Vec<String> = std::env::args().collect();
+
+    let config = match Config::load(&mut args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("❌ {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let command = args.get(1).map(|s| s.as_str()).unwrap_or("help");
+
+    let mut tenpod = TenPod::new(config);
+    tenpod.force = args.iter().any(|a| a == "--force");
+    tenpod.dry_run = args.iter().any(|a| a == "--dry-run");
+
+    match command {
+        "install" if tenpod.dry_run => {
+            if let Err(e) = tenpod.install() {
+                eprintln!("\n❌ {}", e);
+                std::process::exit(1);
+            }
+        }
+
+        "install" => {
+            if let Err(e) = tenpod.detect_hardware(true) {
+                eprintln!("❌ Hardware detection failed: {}", e);
+                std::process::exit(1);
+            }
+
+            if let Err(e) = tenpod.check_system() {
+                eprintln!("\n❌ System check failed:");
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+
+            if let Err(e) = tenpod.install() {
+                eprintln!("\n❌ Installation failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+
+        "start" if args.iter().any(|a| a == "--detach") && !tenpod.dry_run && tenpod.config.backend != "libvirt" => {
+            if let Err(e) = tenpod.start_detached() {
+                eprintln!("\n❌ Failed to start VM: {}", e);
+                std::process::exit(1);
+            }
+        }
+
+        "start" if tenpod.dry_run || args.iter().any(|a| a == "--print-cmd") => {
+            if let Err(e) = tenpod.detect_hardware(false) {
+                eprintln!("❌ Hardware detection failed: {}", e);
+                std::process::exit(1);
+            }
+
+            let headsets = tenpod.detect_headsets();
+
+            if let Err(e) = tenpod.print_start_command(&headsets) {
+                eprintln!("❌ {}", e);
+                std::process::exit(1);
+            }
+        }
+
+        "start" => {
+            if let Err(e) = tenpod.detect_hardware(false) {
+                eprintln!("❌ Hardware detection failed: {}", e);
+                std::process::exit(1);
+            }
+
+            if let Err(e) = tenpod.check_system() {
+                eprintln!("\n❌ System check failed:");
+                eprintln!("{}", e);
+                eprintln!("\n💡 Tip: Run './tenpod.rs install' if you haven't already");
+                std::process::exit(1);
+            }
+
+            let headsets = tenpod.detect_headsets();
 
-    let mut tenpod = TenPod::new();
+            let result = if tenpod.config.backend == "libvirt" {
+                tenpod.start_libvirt(headsets)
+            } else {
+                tenpod.start(headsets)
+            };
 
-    match command {
-        "install" => {
-            if let Err(e) = tenpod.detect_hardware() {
+            if let Err(e) = result {
+                eprintln!("\n❌ Failed to start VM: {}", e);
+                std::process::exit(1);
+            }
+        }
+
+        "export-xml" => {
+            if let Err(e) = tenpod.detect_hardware(false) {
                 eprintln!("❌ Hardware detection failed: {}", e);
                 std::process::exit(1);
             }
 
-            if let Err(e) = tenpod.check_system() {
-                eprintln!("\n❌ System check failed:");
-                eprintln!("{}", e);
+            let headsets = tenpod.detect_headsets();
+
+            let output = args.get(2)
+            .filter(|a| !a.starts_with("--"))
+            .cloned()
+            .unwrap_or_else(|| format!("{}.xml", tenpod.config.name));
+
+            if let Err(e) = tenpod.export_xml(&headsets, &output) {
+                eprintln!("❌ {}", e);
                 std::process::exit(1);
             }
+        }
 
-            if let Err(e) = tenpod.install() {
-                eprintln!("\n❌ Installation failed: {}", e);
+        "kernel-args" => {
+            let with_vfio_ids = args.iter().any(|a| a == "--vfio-ids");
+            if with_vfio_ids {
+                if let Err(e) = tenpod.detect_hardware(false) {
+                    eprintln!("❌ Hardware detection failed: {}", e);
+                    std::process::exit(1);
+                }
+            }
+
+            let isolate = tenpod.config.isolate_kernel_args || args.iter().any(|a| a == "--isolate");
+            if isolate && !with_vfio_ids && tenpod.cpus.nodes.len() > 1 {
+                if let Err(e) = tenpod.detect_hardware(false) {
+                    println!("⚠️  {}", e);
+                }
+            }
+
+            if let Err(e) = tenpod.kernel_args(with_vfio_ids, isolate) {
+                eprintln!("\n❌ {}", e);
                 std::process::exit(1);
             }
         }
 
-        "start" => {
-            if let Err(e) = tenpod.detect_hardware() {
+        "bind" | "unbind" => {
+            if let Err(e) = tenpod.detect_hardware(false) {
                 eprintln!("❌ Hardware detection failed: {}", e);
                 std::process::exit(1);
             }
 
-            if let Err(e) = tenpod.check_system() {
-                eprintln!("\n❌ System check failed:");
-                eprintln!("{}", e);
-                eprintln!("\n💡 Tip: Run './tenpod.rs install' if you haven't already");
+            let result = if command == "bind" { tenpod.bind_vfio() } else { tenpod.unbind_vfio() };
+            if let Err(e) = result {
+                eprintln!("\n❌ {}", e);
                 std::process::exit(1);
             }
+        }
 
-            let headsets = tenpod.detect_headsets();
+        "service" => {
+            let action = args.get(2).map(|s| s.as_str()).unwrap_or("");
+            let system = args.iter().any(|a| a == "--system");
 
-            if let Err(e) = tenpod.start(headsets) {
-                eprintln!("\n❌ Failed to start VM: {}", e);
+            // Config options are already in tenpod.config.cli_options; what's left after
+            // `service install` apart from our own flags is for start (e.g. --force)
+            let start_flags: Vec<String> = args.iter().skip(3)
+            .filter(|a| *a != "--system" && *a != "--dry-run")
+            .cloned()
+            .collect();
+
+            if let Err(e) = tenpod.service(action, system, &start_flags) {
+                eprintln!("❌ {}", e);
+                std::process::exit(1);
+            }
+        }
+
+        "topology" => {
+            // With vm.numa_node = auto the GPU decides where the VM goes
+            if tenpod.cpus.nodes.len() > 1 {
+                if let Err(e) = tenpod.detect_hardware(false) {
+                    println!("⚠️  {}", e);
+                }
+            }
+
+            if let Err(e) = tenpod.topology() {
+                eprintln!("\n❌ {}", e);
+                std::process::exit(1);
+            }
+        }
+
+        "logs" => {
+            let follow = args.iter().any(|a| a == "-f" || a == "--follow");
+
+            if let Err(e) = tenpod.logs(follow) {
+                eprintln!("❌ {}", e);
+                std::process::exit(1);
+            }
+        }
+
+        "pause" | "resume" | "reset" => {
+            if let Err(e) = tenpod.control(command) {
+                eprintln!("❌ {}", e);
                 std::process::exit(1);
             }
         }
 
         "stop" => {
-            if let Err(e) = tenpod.stop() {
+            // --kill is the older spelling of --force
+            if let Err(e) = tenpod.stop(args.iter().any(|a| a == "--force" || a == "--kill")) {
                 eprintln!("❌ {}", e);
                 std::process::exit(1);
             }
         }
 
         "status" => {
-            if let Err(e) = tenpod.detect_hardware() {
+            if let Err(e) = tenpod.detect_hardware(false) {
                 eprintln!("❌ Hardware detection failed: {}", e);
                 std::process::exit(1);
             }
             tenpod.status();
         }
 
+        "config" => {
+            if tenpod.config.sources.is_empty() {
+                println!("# No config file found, using defaults");
+                println!("# System: {}", SYSTEM_CONFIG_PATH);
+                if let Some(path) = Config::user_path() {
+                    println!("# User:   {}", path);
+                }
+            } else {
+                println!("# Loaded from: {}", tenpod.config.sources.join(", "));
+            }
+            println!("{}", tenpod.config.render());
+        }
+
+        "uninstall" => {
+            let remove_disk = args.iter().any(|a| a == "--remove-disk");
+
+            if let Err(e) = tenpod.uninstall(remove_disk) {
+                eprintln!("\n❌ Uninstall failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+
         _ => {
             print_help();
         }
     }
 }
+
+// ============================================================================
+// TESTS
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    /// A scratch directory standing in for /sys, removed when dropped
+    struct FakeSys {
+        root: PathBuf,
+    }
+
+    impl FakeSys {
+        fn new(name: &str) -> Self {
+            let root = std::env::temp_dir().join(format!("tenpod-test-{}-{}", name, std::process::id()));
+            fs::remove_dir_all(&root).ok();
+            fs::create_dir_all(&root).unwrap();
+            Self { root }
+        }
+
+        fn write(&self, path: &str, contents: &str) {
+            let path = self.root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+
+        fn link(&self, path: &str, target: &str) {
+            let path = self.root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            symlink(self.root.join(target), path).unwrap();
+        }
+
+        /// A logical CPU with its SMT siblings, package and the CPUs sharing its L3 cache
+        fn cpu(&self, cpu: u32, siblings: &str, package: u32, l3: &str) {
+            let dir = format!("devices/system/cpu/cpu{}", cpu);
+            self.write(&format!("{}/topology/thread_siblings_list", dir), &format!("{}\n", siblings));
+            self.write(&format!("{}/topology/physical_package_id", dir), &format!("{}\n", package));
+            self.write(&format!("{}/cache/index0/level", dir), "1\n");
+            self.write(&format!("{}/cache/index0/shared_cpu_list", dir), &format!("{}\n", siblings));
+            self.write(&format!("{}/cache/index3/level", dir), "3\n");
+            self.write(&format!("{}/cache/index3/shared_cpu_list", dir), &format!("{}\n", l3));
+        }
+
+        /// A PCI function with its ids, class, IOMMU group and (optionally) bound driver
+        fn pci(&self, addr: &str, id: &str, class: &str, group: u32, driver: Option<&str>) {
+            let dir = format!("bus/pci/devices/{}", addr);
+            let (vendor, device) = id.split_once(':').unwrap();
+            self.write(&format!("{}/vendor", dir), &format!("0x{}\n", vendor));
+            self.write(&format!("{}/device", dir), &format!("0x{}\n", device));
+            self.write(&format!("{}/class", dir), &format!("0x{}\n", class));
+
+            let group_dir = format!("kernel/iommu_groups/{}", group);
+            fs::create_dir_all(self.root.join(&group_dir).join("devices")).unwrap();
+            self.link(&format!("{}/iommu_group", dir), &group_dir);
+            self.link(&format!("{}/devices/{}", group_dir, addr), &dir);
+
+            if let Some(driver) = driver {
+                fs::create_dir_all(self.root.join("bus/pci/drivers").join(driver)).unwrap();
+                // Modules as named on Linux; xhci_hcd comes from xhci_pci, pcieport is built in
+                let module = match driver {
+                    "xhci_hcd" => Some("xhci_pci"),
+                    "pcieport" => None,
+                    other => Some(other),
+                };
+                if let Some(module) = module {
+                    fs::create_dir_all(self.root.join("module").join(module)).unwrap();
+                    self.link(&format!("bus/pci/drivers/{}/module", driver), &format!("module/{}", module));
+                }
+                self.link(&format!("{}/driver", dir), &format!("bus/pci/drivers/{}", driver));
+            }
+        }
+    }
+
+    impl Drop for FakeSys {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.root).ok();
+        }
+    }
+
+    /// A discrete NVIDIA card with four functions sharing group 14, next to an iGPU and a bridge
+    fn fake_pci(name: &str) -> FakeSys {
+        let sys = FakeSys::new(name);
+        sys.pci("0000:00:02.0", "8086:3e92", "030000", 1, Some("i915"));
+        sys.write("bus/pci/devices/0000:00:02.0/boot_vga", "1\n");
+        sys.pci("0000:00:01.0", "8086:1901", "060400", 2, Some("pcieport"));
+        sys.pci("0000:01:00.0", "10DE:1F08", "030000", 14, Some("nvidia"));
+        sys.write("bus/pci/devices/0000:01:00.0/boot_vga", "0\n");
+        sys.pci("0000:01:00.1", "10de:10f9", "040300", 14, Some("snd_hda_intel"));
+        sys.pci("0000:01:00.2", "10de:1ada", "0c0330", 14, Some("xhci_hcd"));
+        sys.pci("0000:01:00.3", "10de:1adb", "0c8000", 14, None);
+        sys.pci("0000:02:00.0", "144d:a808", "010802", 15, Some("nvme"));
+        sys
+    }
+
+    #[test]
+    fn pci_enumerates_devices_in_address_order() {
+        let sys = fake_pci("pci-enum");
+        let pci = PciBus::new(&sys.root);
+
+        let addresses: Vec<String> = pci.devices().into_iter().map(|d| d.address).collect();
+        assert_eq!(addresses, [
+            "0000:00:01.0", "0000:00:02.0", "0000:01:00.0", "0000:01:00.1",
+            "0000:01:00.2", "0000:01:00.3", "0000:02:00.0",
+        ]);
+
+        let gpu = pci.device("0000:01:00.0").unwrap();
+        assert_eq!(gpu.id(), "10de:1f08");
+        assert_eq!(gpu.class, 0x030000);
+        assert_eq!(gpu.driver.as_deref(), Some("nvidia"));
+        assert_eq!(gpu.iommu_group.as_deref(), Some("14"));
+        assert!(gpu.is_display() && !gpu.boot_vga);
+
+        assert!(pci.device("0000:00:02.0").unwrap().boot_vga);
+        assert!(pci.device("0000:00:01.0").unwrap().is_bridge());
+        assert_eq!(pci.device("0000:01:00.3").unwrap().driver, None);
+        assert!(pci.device("0000:09:00.0").is_none());
+    }
+
+    #[test]
+    fn pci_skips_entries_without_ids() {
+        let sys = fake_pci("pci-partial");
+        fs::create_dir_all(sys.root.join("bus/pci/devices/0000:03:00.0")).unwrap();
+
+        let pci = PciBus::new(&sys.root);
+        assert_eq!(pci.devices().len(), 7);
+        assert!(pci.device("0000:03:00.0").is_none());
+    }
+
+    #[test]
+    fn pci_groups_functions_by_slot() {
+        let sys = fake_pci("pci-slot");
+        let pci = PciBus::new(&sys.root);
+
+        let functions = pci.slot_functions("0000:01:00.0");
+        let roles: Vec<(&str, &str)> = functions.iter().map(|f| (f.address.as_str(), f.role())).collect();
+        assert_eq!(roles, [
+            ("0000:01:00.0", "Video"),
+            ("0000:01:00.1", "Audio"),
+            ("0000:01:00.2", "USB"),
+            ("0000:01:00.3", "USB-C (UCSI)"),
+        ]);
+        assert!(functions[1].is_audio());
+
+        // Any function finds the whole card
+        assert_eq!(pci.slot_functions("0000:01:00.2").len(), 4);
+        assert_eq!(pci.slot_functions("0000:02:00.0").len(), 1);
+        assert_eq!(pci_slot("0000:01:00.1"), "0000:01:00");
+    }
+
+    #[test]
+    fn pci_reads_iommu_groups() {
+        let sys = fake_pci("pci-iommu");
+        let pci = PciBus::new(&sys.root);
+
+        assert_eq!(pci.iommu_group_count(), 4);
+        assert_eq!(pci.iommu_group_devices("14"), [
+            "0000:01:00.0", "0000:01:00.1", "0000:01:00.2", "0000:01:00.3",
+        ]);
+        assert_eq!(pci.iommu_group_devices("15"), ["0000:02:00.0"]);
+        assert!(pci.iommu_group_devices("99").is_empty());
+
+        // IOMMU off: no groups at all
+        let empty = FakeSys::new("pci-no-iommu");
+        assert_eq!(PciBus::new(&empty.root).iommu_group_count(), 0);
+    }
+
+    #[test]
+    fn grub_assignment_words_skip_variables() {
+        let grub = "GRUB_TIMEOUT=5\nGRUB_CMDLINE_LINUX_DEFAULT=\"quiet splash intel_iommu=on\"\n";
+        assert_eq!(assignment_words(grub, "GRUB_CMDLINE_LINUX_DEFAULT"), ["quiet", "splash", "intel_iommu=on"]);
+        assert!(assignment_words(grub, "GRUB_CMDLINE_LINUX").is_empty());
+
+        let drop_in = "# Ten Pod\nGRUB_CMDLINE_LINUX_DEFAULT=\"${GRUB_CMDLINE_LINUX_DEFAULT} amd_iommu=on iommu=pt\"\n";
+        assert_eq!(assignment_words(drop_in, "GRUB_CMDLINE_LINUX_DEFAULT"), ["amd_iommu=on", "iommu=pt"]);
+    }
+
+    #[test]
+    fn grub_append_keeps_existing_words() {
+        let grub = "GRUB_TIMEOUT=5\nGRUB_CMDLINE_LINUX_DEFAULT=\"quiet\"\n";
+        assert_eq!(
+            append_to_assignment(grub, "GRUB_CMDLINE_LINUX_DEFAULT", "iommu=pt"),
+            "GRUB_TIMEOUT=5\nGRUB_CMDLINE_LINUX_DEFAULT=\"quiet iommu=pt\"\n"
+        );
+        assert_eq!(
+            append_to_assignment("GRUB_TIMEOUT=5\n", "GRUB_CMDLINE_LINUX_DEFAULT", "iommu=pt"),
+            "GRUB_TIMEOUT=5\nGRUB_CMDLINE_LINUX_DEFAULT=\"iommu=pt\"\n"
+        );
+    }
+
+    /// A VM with the GPU from fake_pci() and no install ISO
+    fn test_vm(config: Config) -> TenPod {
+        let mut tenpod = TenPod::new(Config { iso_path: "/nonexistent/win10.iso".to_string(), ..config });
+        tenpod.gpu_pci = "0000:01:00.0".to_string();
+        tenpod.gpu_functions = ["0000:01:00.0", "0000:01:00.1"].iter().map(|address| PciDevice {
+            address: address.to_string(),
+            vendor: "10de".to_string(),
+            device: "1f08".to_string(),
+            class: 0x030000,
+            driver: Some("vfio-pci".to_string()),
+            iommu_group: Some("14".to_string()),
+            boot_vga: false,
+        }).collect();
+        tenpod
+    }
+
+    fn test_plan(node: Option<u32>) -> CpuPlan {
+        CpuPlan { vcpus: vec![2, 3, 10, 11], emulator: vec![1, 9], sockets: 1, cores: 2, threads: 2, one_cache: true, node }
+    }
+
+    /// Every value given for `flag`, in order
+    fn values<'a>(argv: &'a [String], flag: &str) -> Vec<&'a str> {
+        argv.windows(2).filter(|w| w[0] == flag).map(|w| w[1].as_str()).collect()
+    }
+
+    #[test]
+    fn qemu_opts_render_and_escape_commas() {
+        let opts = QemuOpts::new("socket").set("id", "chr0").set("path", "/run/a,b.sock").flag("server");
+        assert_eq!(opts.render(), "socket,id=chr0,path=/run/a,,b.sock,server");
+        assert_eq!(QemuOpts::props().set("if", "pflash").set("readonly", "on").render(), "if=pflash,readonly=on");
+    }
+
+    #[test]
+    fn qemu_argv_keeps_group_order() {
+        let mut qemu = QemuCommand::new("qemu-system-x86_64");
+        qemu.name = "Test".to_string();
+        qemu.machine = QemuOpts::new("q35");
+        qemu.cpu = QemuOpts::new("host");
+        qemu.smp = "2".to_string();
+        qemu.memory_gb = 4;
+        qemu.device(QemuOpts::new("qemu-xhci"));
+        qemu.object(QemuOpts::new("iothread").set("id", "io0"));
+        qemu.option("-vga", "none");
+        qemu.flag("-nographic");
+        qemu.extra = vec!["-snapshot".to_string()];
+
+        assert_eq!(qemu.argv(), [
+            "qemu-system-x86_64", "-name", "Test", "-machine", "q35", "-cpu", "host", "-smp", "2",
+            "-m", "4G", "-enable-kvm", "-object", "iothread,id=io0", "-device", "qemu-xhci",
+            "-vga", "none", "-nographic", "-snapshot",
+        ]);
+    }
+
+    #[test]
+    fn qemu_command_runs_under_taskset_on_emulator_cpus() {
+        let tenpod = test_vm(Config { tpm: false, ..Config::new() });
+        let argv = tenpod.qemu_command(&[], None, &test_plan(None), None).argv();
+
+        assert_eq!(argv[..4], ["taskset", "-c", "1,9", "qemu-system-x86_64"]);
+        assert_eq!(values(&argv, "-smp"), ["4,sockets=1,cores=2,threads=2"]);
+        assert_eq!(values(&argv, "-m"), ["16G"]);
+        assert!(argv.contains(&"-S".to_string()));
+        assert!(!argv.contains(&"-cdrom".to_string()));
+        assert!(values(&argv, "-drive").iter().all(|d| !d.contains("pflash")));
+        assert!(values(&argv, "-device").iter().all(|d| !d.starts_with("tpm-crb")));
+        assert_eq!(values(&argv, "-machine"), ["q35,accel=kvm,kernel_irqchip=on"]);
+
+        // All functions of the card behind one root port, keeping their function numbers
+        let devices = values(&argv, "-device");
+        assert!(devices.contains(&"vfio-pci,host=0000:01:00.0,bus=gpu-port,addr=00.0,multifunction=on"));
+        assert!(devices.contains(&"vfio-pci,host=0000:01:00.1,bus=gpu-port,addr=00.1"));
+
+        let line = tenpod.qemu_command(&[], None, &test_plan(None), None).shell_line();
+        assert!(line.starts_with("taskset -c 1,9 qemu-system-x86_64 -name TenPod "));
+    }
+
+    #[test]
+    fn qemu_command_with_uefi_and_tpm() {
+        let tenpod = test_vm(Config { secure_boot: true, ..Config::new() });
+        let ovmf = Ovmf {
+            code: "/usr/share/OVMF/OVMF_CODE.secboot.fd".to_string(),
+            vars: "/usr/share/OVMF/OVMF_VARS.secboot.fd".to_string(),
+            secure_boot: true,
+        };
+        let argv = tenpod.qemu_command(&[], Some(&ovmf), &test_plan(None), None).argv();
+
+        let drives = values(&argv, "-drive");
+        assert_eq!(drives[..2], [
+            "if=pflash,format=raw,readonly=on,file=/usr/share/OVMF/OVMF_CODE.secboot.fd".to_string(),
+            format!("if=pflash,format=raw,file={}", tenpod.nvram_path()),
+        ]);
+        assert!(tenpod.nvram_path().ends_with("/OVMF_VARS.secboot.fd"));
+        assert_eq!(values(&argv, "-machine"), ["q35,accel=kvm,kernel_irqchip=on,smm=on"]);
+        assert!(values(&argv, "-global").contains(&"driver=cfi.pflash01,property=secure,value=on"));
+
+        let socket = tenpod.runtime_dir().join("swtpm.sock");
+        assert_eq!(values(&argv, "-chardev"), [format!("socket,id=chrtpm,path={}", socket.display())]);
+        assert_eq!(values(&argv, "-tpmdev"), ["emulator,id=tpm0,chardev=chrtpm"]);
+        assert!(values(&argv, "-device").contains(&"tpm-crb,tpmdev=tpm0"));
+    }
+
+    #[test]
+    fn qemu_command_binds_hugepages_to_numa_node() {
+        let tenpod = test_vm(Config { memory_gb: 8, ..Config::new() });
+        let pool = HugePagePool { size_kb: HUGEPAGE_1G_KB, node: Some(1), pages: 8, added: 8, mounted: true };
+        let argv = tenpod.qemu_command(&[], None, &test_plan(Some(1)), Some(&pool)).argv();
+
+        assert_eq!(values(&argv, "-object")[0],
+            "memory-backend-file,id=mem0,size=8G,mem-path=/dev/hugepages1G,prealloc=on,host-nodes=1,policy=bind");
+        assert_eq!(values(&argv, "-machine"), ["q35,accel=kvm,kernel_irqchip=on,memory-backend=mem0"]);
+
+        // NUMA binding without hugepages still gets an explicit backend
+        let argv = tenpod.qemu_command(&[], None, &test_plan(Some(0)), None).argv();
+        assert_eq!(values(&argv, "-object")[0], "memory-backend-ram,id=mem0,size=8G,host-nodes=0,policy=bind");
+
+        // Neither: plain -m
+        let argv = tenpod.qemu_command(&[], None, &test_plan(None), None).argv();
+        assert!(values(&argv, "-object").iter().all(|o| !o.contains("mem0")));
+        assert_eq!(values(&argv, "-machine"), ["q35,accel=kvm,kernel_irqchip=on"]);
+    }
+
+    #[test]
+    fn shell_quote_only_quotes_when_needed() {
+        assert_eq!(shell_quote("-device"), "-device");
+        assert_eq!(shell_quote("vfio-pci,host=0000:01:00.0"), "vfio-pci,host=0000:01:00.0");
+        assert_eq!(shell_quote("/home/me/Windows VMs/win.qcow2"), "'/home/me/Windows VMs/win.qcow2'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        assert_eq!(shell_quote("say \"hi\""), "'say \"hi\"'");
+        assert_eq!(shell_quote("$HOME"), "'$HOME'");
+        assert_eq!(shell_quote(""), "''");
+
+        let mut qemu = QemuCommand::new("qemu-system-x86_64");
+        qemu.name = "Ten Pod's VM".to_string();
+        qemu.machine = QemuOpts::new("q35");
+        qemu.cpu = QemuOpts::new("host");
+        qemu.smp = "2".to_string();
+        qemu.memory_gb = 4;
+        qemu.option("-cdrom", "/isos/Win 11.iso");
+        assert_eq!(qemu.shell_line(), concat!(
+            "qemu-system-x86_64 -name 'Ten Pod'\\''s VM' -machine q35 -cpu host -smp 2 -m 4G -enable-kvm ",
+            "-cdrom '/isos/Win 11.iso'",
+        ));
+    }
+
+    #[test]
+    fn json_parses_qmp_replies() {
+        let reply = Json::parse(r#"{"return": [{"cpu-index": 0, "thread-id": 4242, "props": {"core-id": 0}}, {"cpu-index": 1, "thread-id": 4243}]}"#).unwrap();
+        let cpus = reply.get("return").unwrap().as_array();
+        assert_eq!(cpus.len(), 2);
+        assert_eq!(cpus[1].get("thread-id").and_then(|t| t.as_u64()), Some(4243));
+        assert_eq!(cpus[0].get("props").and_then(|p| p.get("core-id")).and_then(|c| c.as_u64()), Some(0));
+
+        let status = Json::parse(" {\"running\": true, \"singlestep\": false, \"status\": \"running\", \"x\": null} ").unwrap();
+        assert_eq!(status.get("running").and_then(|r| r.as_bool()), Some(true));
+        assert_eq!(status.get("status").and_then(|s| s.as_str()), Some("running"));
+        assert!(matches!(status.get("x"), Some(Json::Null)));
+        assert!(status.get("missing").is_none());
+
+        assert!(matches!(Json::parse("[]").unwrap(), Json::Array(items) if items.is_empty()));
+        assert!(matches!(Json::parse("{}").unwrap(), Json::Object(fields) if fields.is_empty()));
+        assert!(matches!(Json::parse("-1.5e3").unwrap(), Json::Number(n) if n == -1500.0));
+        assert_eq!(Json::parse("-1").unwrap().as_u64(), None);
+    }
+
+    #[test]
+    fn json_decodes_string_escapes() {
+        let text = |json: &str| Json::parse(json).unwrap().as_str().unwrap().to_string();
+        assert_eq!(text(r#""a\"b\\c\/d\n\t""#), "a\"b\\c/d\n\t");
+        assert_eq!(text(r#""caf\u00e9 \u20ac""#), "café €");
+        assert_eq!(text(r#""\uD83D\uDE00!""#), "😀!");
+        assert_eq!(text(r#""\ud83d\ude00""#), "😀");
+
+        // Unpaired surrogates can't be represented, but don't swallow what follows
+        assert_eq!(text(r#""\uD83Dx""#), "\u{fffd}x");
+        assert_eq!(text(r#""\uD83D\u0041""#), "\u{fffd}A");
+        assert_eq!(text(r#""\uDE00""#), "\u{fffd}");
+    }
+
+    #[test]
+    fn json_rejects_malformed_input() {
+        for bad in ["", "{", "[1,", "[1 2]", "{\"a\" 1}", "\"open", "\"\\u12\"", "\"\\u+123\"", "nul", "{} x", "01x"] {
+            assert!(Json::parse(bad).is_err(), "{:?} should not parse", bad);
+        }
+    }
+
+    /// Serve one QMP client: send the greeting, then answer each expected command in turn
+    fn fake_qmp(name: &str, script: Vec<(&'static str, Vec<&'static str>)>) -> (PathBuf, thread::JoinHandle<()>) {
+        let path = std::env::temp_dir().join(format!("tenpod-test-{}-{}.sock", name, std::process::id()));
+        fs::remove_file(&path).ok();
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            writer.write_all(concat!(
+                r#"{"QMP": {"version": {"qemu": {"micro": 1, "minor": 2, "major": 8}, "package": ""}, "#,
+                r#""capabilities": ["oob"]}}"#, "\n",
+            ).as_bytes()).unwrap();
+
+            for (command, replies) in script {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let request = Json::parse(line.trim()).unwrap();
+                assert_eq!(request.get("execute").and_then(|e| e.as_str()), Some(command));
+                for reply in replies {
+                    writer.write_all(format!("{}\n", reply).as_bytes()).unwrap();
+                }
+            }
+        });
+        (path, server)
+    }
+
+    #[test]
+    fn qmp_negotiates_and_skips_events() {
+        let (path, server) = fake_qmp("qmp", vec![
+            ("qmp_capabilities", vec![r#"{"return": {}}"#]),
+            ("query-cpus-fast", vec![
+                r#"{"timestamp": {"seconds": 1, "microseconds": 2}, "event": "RESUME"}"#,
+                r#"{"event": "RTC_CHANGE", "data": {"offset": 0}}"#,
+                r#"{"return": [{"cpu-index": 0, "thread-id": 101}, {"cpu-index": 1, "thread-id": 102}]}"#,
+            ]),
+            ("query-status", vec![r#"{"return": {"status": "paused", "running": false}}"#]),
+            ("cont", vec![r#"{"error": {"class": "GenericError", "desc": "Resetting the Virtual Machine is required"}}"#]),
+        ]);
+
+        let mut qmp = Qmp::connect(&path).unwrap();
+        assert_eq!(qmp.version, "8.2.1");
+        assert_eq!(qmp.vcpu_threads().unwrap(), [101, 102]);
+        assert_eq!(qmp.run_state().unwrap(), "paused");
+        assert_eq!(
+            qmp.execute("cont", None).err().unwrap(),
+            "QMP cont failed: Resetting the Virtual Machine is required"
+        );
+
+        server.join().unwrap();
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn qmp_rejects_sockets_without_greeting() {
+        let path = std::env::temp_dir().join(format!("tenpod-test-not-qmp-{}.sock", std::process::id()));
+        fs::remove_file(&path).ok();
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"{\"hello\": 1}\n").unwrap();
+        });
+
+        assert_eq!(Qmp::connect(&path).err().unwrap(), "Not a QMP socket (no greeting)");
+        server.join().unwrap();
+        fs::remove_file(&path).ok();
+        assert!(Qmp::connect(&path).is_err());
+    }
+
+    /// Intel hybrid: 8 P-cores with SMT (CPUs 0-15) and 8 E-cores (16-23), one shared L3
+    fn fake_hybrid(name: &str) -> FakeSys {
+        let sys = FakeSys::new(name);
+        sys.write("devices/system/cpu/online", "0-23\n");
+        for cpu in 0..16 {
+            let first = cpu & !1;
+            sys.cpu(cpu, &format!("{}-{}", first, first + 1), 0, "0-23");
+        }
+        for cpu in 16..24 {
+            sys.cpu(cpu, &cpu.to_string(), 0, "0-23");
+        }
+        sys.write("devices/cpu_atom/cpus", "16-23\n");
+        sys.write("devices/system/node/online", "0\n");
+        sys.write("devices/system/node/node0/cpulist", "0-23\n");
+        sys
+    }
+
+    /// Two CCXs on two NUMA nodes: cores 0-7 with SMT siblings n and n+8,
+    /// L3 and node 0 = CPUs 0-3,8-11, L3 and node 1 = CPUs 4-7,12-15
+    fn fake_two_ccx(name: &str) -> FakeSys {
+        let sys = FakeSys::new(name);
+        sys.write("devices/system/cpu/online", "0-15\n");
+        for cpu in 0..16 {
+            let core = cpu % 8;
+            let l3 = if core < 4 { "0-3,8-11" } else { "4-7,12-15" };
+            sys.cpu(cpu, &format!("{},{}", core, core + 8), 0, l3);
+        }
+        sys.write("devices/system/node/online", "0-1\n");
+        sys.write("devices/system/node/node0/cpulist", "0-3,8-11\n");
+        sys.write("devices/system/node/node1/cpulist", "4-7,12-15\n");
+        sys
+    }
+
+    fn core_cpus(cores: &[&HostCore]) -> Vec<Vec<u32>> {
+        cores.iter().map(|c| c.cpus.clone()).collect()
+    }
+
+    #[test]
+    fn topology_reads_cores_and_caches() {
+        let sys = fake_two_ccx("cpu-read");
+        let cpus = CpuTopology::new(&sys.root);
+
+        assert_eq!(cpus.online.len(), 16);
+        assert_eq!(cpus.cores.len(), 8);
+        assert_eq!(cpus.cores[2].cpus, [2, 10]);
+        assert_eq!(cpus.cores[7].l3, [4, 5, 6, 7, 12, 13, 14, 15]);
+        assert_eq!(cpus.cache_domains().len(), 2);
+        assert_eq!(cpus.node_of(5), Some(1));
+        assert_eq!(cpus.node_of(8), Some(0));
+
+        // One node: no NUMA binding at all
+        let hybrid = fake_hybrid("cpu-read-hybrid");
+        let cpus = CpuTopology::new(&hybrid.root);
+        assert_eq!(cpus.cores.len(), 16);
+        assert_eq!(cpus.cores.iter().filter(|c| c.efficiency).count(), 8);
+        assert_eq!(cpus.node_of(5), None);
+    }
+
+    #[test]
+    fn select_cores_skips_e_cores_and_cpu0() {
+        let sys = fake_hybrid("cpu-hybrid");
+        let cpus = CpuTopology::new(&sys.root);
+
+        let (cores, one_cache) = cpus.select_cores(4, None).unwrap();
+        assert_eq!(core_cpus(&cores), [vec![8, 9], vec![10, 11], vec![12, 13], vec![14, 15]]);
+        assert!(one_cache);
+
+        // Seven P-cores besides CPU 0's: an eighth core is an error, not an E-core
+        let (cores, _) = cpus.select_cores(7, None).unwrap();
+        assert!(cores.iter().all(|c| !c.efficiency && !c.cpus.contains(&0)));
+        let err = cpus.select_cores(8, None).err().unwrap();
+        assert!(err.contains("only has 7 cores to spare"), "{}", err);
+
+        // Without P-cores (an E-core only machine) E-cores are fine
+        let atoms = FakeSys::new("cpu-atoms");
+        atoms.write("devices/system/cpu/online", "0-3\n");
+        for cpu in 0..4 {
+            atoms.cpu(cpu, &cpu.to_string(), 0, "0-3");
+        }
+        atoms.write("devices/cpu_atom/cpus", "0-3\n");
+        let cpus = CpuTopology::new(&atoms.root);
+        assert_eq!(core_cpus(&cpus.select_cores(3, None).unwrap().0), [vec![1], vec![2], vec![3]]);
+    }
+
+    #[test]
+    fn select_cores_prefers_one_cache_domain_away_from_host() {
+        let sys = fake_two_ccx("cpu-ccx");
+        let cpus = CpuTopology::new(&sys.root);
+
+        // The CCX without CPU 0 is left entirely to the guest
+        let (cores, one_cache) = cpus.select_cores(4, None).unwrap();
+        assert_eq!(core_cpus(&cores), [vec![4, 12], vec![5, 13], vec![6, 14], vec![7, 15]]);
+        assert!(one_cache);
+
+        let (cores, one_cache) = cpus.select_cores(2, None).unwrap();
+        assert_eq!(core_cpus(&cores), [vec![6, 14], vec![7, 15]]);
+        assert!(one_cache);
+
+        // Too big for one CCX: spread, still never CPU 0's core
+        let (cores, one_cache) = cpus.select_cores(6, None).unwrap();
+        assert_eq!(core_cpus(&cores), [vec![2, 10], vec![3, 11], vec![4, 12], vec![5, 13], vec![6, 14], vec![7, 15]]);
+        assert!(!one_cache);
+        assert!(cpus.select_cores(8, None).is_err());
+    }
+
+    #[test]
+    fn select_cores_stays_on_numa_node() {
+        let sys = fake_two_ccx("cpu-numa");
+        let cpus = CpuTopology::new(&sys.root);
+
+        let (cores, one_cache) = cpus.select_cores(3, Some(0)).unwrap();
+        assert_eq!(core_cpus(&cores), [vec![1, 9], vec![2, 10], vec![3, 11]]);
+        assert!(one_cache);
+
+        let (cores, _) = cpus.select_cores(4, Some(1)).unwrap();
+        assert!(cores.iter().all(|c| cpus.node_of(c.cpus[0]) == Some(1)));
+
+        let err = cpus.select_cores(4, Some(0)).err().unwrap();
+        assert!(err.contains("only has 3 cores to spare on NUMA node 0"), "{}", err);
+    }
+
+    #[test]
+    fn config_strips_trailing_comments() {
+        assert_eq!(strip_comment(" 16        # Guest RAM"), "16");
+        assert_eq!(strip_comment("TenPod # VM name"), "TenPod");
+        assert_eq!(strip_comment("auto ; inline"), "auto");
+        assert_eq!(strip_comment("\"a # b\"  # quoted"), "\"a # b\"");
+        assert_eq!(strip_comment("/vms/win#2.qcow2"), "/vms/win#2.qcow2");
+        assert_eq!(strip_comment("   "), "");
+    }
+
+    /// Load a config file on top of the defaults
+    fn load_config(name: &str, contents: &str) -> Result<Config, String> {
+        let sys = FakeSys::new(name);
+        sys.write("tenpod.conf", contents);
+        let path = sys.root.join("tenpod.conf").display().to_string();
+        let mut config = Config::new();
+        config.load_file(&path)?;
+        assert_eq!(config.sources, [path]);
+        Ok(config)
+    }
+
+    #[test]
+    fn config_loads_documented_example() {
+        // The example from the README, comments and all
+        let config = load_config("config-doc", concat!(
+            "[vm]\n",
+            "name = TenPod         # VM name (also names its UEFI variable store)\n",
+            "backend = qemu        # qemu (run QEMU directly) or libvirt (see below)\n",
+            "shutdown_timeout = 60 # Seconds Windows gets to shut down on ./tenpod stop\n",
+            "memory_gb = 16        # Guest RAM\n",
+            "cpu_cores = auto      # Host CPUs for the vCPUs: auto, or a list like 4-7,12-15\n",
+            "emulator_cores = auto # Host CPUs for QEMU's own threads (auto = everything else)\n",
+            "numa_node = auto      # Keep vCPUs and memory on the GPU's NUMA node (auto | off | node number)\n",
+            "cores = 4             # Physical cores for the guest (SMT siblings come along)\n",
+            "sockets = 1           # With an explicit cpu_cores list: guest topology is\n",
+            "threads = 1           #   sockets x cores x threads vCPUs\n\n",
+            "[storage]\n",
+            "disk = /var/lib/libvirt/images/win10_tenpod.qcow2\n",
+            "iso = /var/lib/libvirt/images/win10.iso\n\n",
+            "[firmware]\n",
+            "type = auto           # auto, uefi (OVMF) or bios\n",
+            "secure_boot = false   # Use the Secure Boot OVMF build\n",
+            "# ovmf_code = /path/to/OVMF_CODE.fd   # Only needed if OVMF lives somewhere unusual\n\n",
+            "[tpm]\n",
+            "enabled = true        # Software TPM 2.0 (swtpm), needed by Windows 11\n\n",
+            "[hugepages]\n",
+            "mode = auto           # auto: fall back to normal memory if allocation fails | required | off\n",
+            "size = 2M             # 2M, or 1G on CPUs with pdpe1gb\n\n",
+            "[isolation]\n",
+            "runtime = false       # Keep host tasks off the VM's cores while it runs\n",
+            "kernel_args = false   # Also isolate those cores at boot (isolcpus/nohz_full/rcu_nocbs)\n\n",
+            "[gpu]\n",
+            "address = 0000:01:00.0   # Which GPU to pass through\n",
+            "vendor_reset = false      # AMD reset bug workaround\n\n",
+            "[host]\n",
+            "memory_gb = 4         # RAM the VM must leave to Linux\n",
+            "cpus = 2              # CPUs the VM must leave to Linux\n\n",
+            "[qemu]\n",
+            "extra_args = -device usb-tablet\n",
+        )).unwrap();
+
+        assert_eq!(config.name, "TenPod");
+        assert_eq!(config.memory_gb, 16);
+        assert_eq!(config.shutdown_timeout, 60);
+        assert_eq!(config.cpu_cores, "auto");
+        assert_eq!(config.threads, 1);
+        assert_eq!(config.firmware, "auto");
+        assert_eq!(config.gpu.as_deref(), Some("0000:01:00.0"));
+        assert_eq!(config.hugepage_size, "2M");
+        assert_eq!(config.host_cpus, 2);
+        assert_eq!(config.extra_args, ["-device", "usb-tablet"]);
+        assert!(config.tpm && !config.isolate_host);
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn config_reports_bad_lines() {
+        let err = load_config("config-number", "[vm]\n# Guest\nmemory_gb = lots  # too vague\n").err().unwrap();
+        assert!(err.ends_with(":3: vm.memory_gb must be a whole number, got 'lots'"), "{}", err);
+
+        let err = load_config("config-bool", "[tpm]\nenabled = maybe\n").err().unwrap();
+        assert!(err.ends_with(":2: tpm.enabled must be true or false, got 'maybe'"), "{}", err);
+
+        let err = load_config("config-key", "[vm]\nmemroy_gb = 8\n").err().unwrap();
+        assert!(err.ends_with(":2: unknown setting 'memroy_gb' in [vm]"), "{}", err);
+
+        let err = load_config("config-line", "[vm]\nmemory_gb 8\n").err().unwrap();
+        assert!(err.ends_with(":2: expected 'key = value', got 'memory_gb 8'"), "{}", err);
+
+        // Quotes are dropped, values are kept exactly otherwise
+        let config = load_config("config-quotes", "[storage]\ndisk = \"/vm disks/win.qcow2\"  ; quoted\n").unwrap();
+        assert_eq!(config.disk_path, "/vm disks/win.qcow2");
+    }
+
+    #[test]
+    fn config_set_normalizes_values() {
+        let mut config = Config::new();
+        config.set("vm", "backend", "LibVirt").unwrap();
+        config.set("hugepages", "size", "1g").unwrap();
+        config.set("firmware", "secure_boot", "yes").unwrap();
+        config.set("gpu", "address", "01:00.0").unwrap();
+        assert_eq!(config.backend, "libvirt");
+        assert_eq!(config.hugepage_size, "1G");
+        assert!(config.secure_boot);
+        assert_eq!(config.gpu.as_deref(), Some("0000:01:00.0"));
+
+        config.set("gpu", "address", "").unwrap();
+        assert_eq!(config.gpu, None);
+    }
+
+    #[test]
+    fn config_validate_rejects_inconsistent_settings() {
+        let invalid = |section: &str, key: &str, value: &str| {
+            let mut config = Config::new();
+            config.set(section, key, value).unwrap();
+            config.validate().err().unwrap_or_else(|| panic!("{}.{} = {} should be rejected", section, key, value))
+        };
+
+        assert!(invalid("vm", "name", "Ten Pod").contains("vm.name"));
+        assert!(invalid("vm", "backend", "xen").contains("vm.backend"));
+        assert!(invalid("vm", "memory_gb", "0").contains("vm.memory_gb"));
+        assert!(invalid("vm", "cores", "0").contains("vm.cores"));
+        assert!(invalid("vm", "cpu_cores", "4-5").contains("only 2 host CPUs"));
+        assert!(invalid("firmware", "type", "coreboot").contains("firmware.type"));
+        assert!(invalid("firmware", "ovmf_code", "/ovmf/CODE.fd").contains("ovmf_vars"));
+        assert!(invalid("hugepages", "size", "4K").contains("hugepages.size"));
+        assert!(invalid("vm", "numa_node", "near").contains("vm.numa_node"));
+
+        let mut config = Config::new();
+        config.set("firmware", "type", "bios").unwrap();
+        config.set("firmware", "secure_boot", "true").unwrap();
+        assert!(config.validate().err().unwrap().contains("secure_boot"));
+
+        let mut config = Config::new();
+        config.set("vm", "backend", "libvirt").unwrap();
+        config.set("hugepages", "size", "1G").unwrap();
+        assert!(config.validate().is_err());
+        config.set("hugepages", "mode", "off").unwrap();
+        config.validate().unwrap();
+
+        Config::new().validate().unwrap();
+    }
+
+    #[test]
+    fn systemd_quote_escapes_specials() {
+        assert_eq!(systemd_quote("--memory"), "--memory");
+        assert_eq!(systemd_quote("/home/me/tenpod.rs"), "/home/me/tenpod.rs");
+        assert_eq!(systemd_quote("vm.cores=6"), "vm.cores=6");
+        assert_eq!(systemd_quote("/home/me/VR Games/tenpod.rs"), "\"/home/me/VR Games/tenpod.rs\"");
+        assert_eq!(systemd_quote("50%"), "50%%");
+        assert_eq!(systemd_quote(r#"say "hi""#), r#""say \"hi\"""#);
+        assert_eq!(systemd_quote("$HOME"), "\"$$HOME\"");
+        assert_eq!(systemd_quote("a\\b c"), "\"a\\\\b c\"");
+        assert_eq!(systemd_quote(""), "\"\"");
+    }
+
+    #[test]
+    fn unit_file_passes_options() {
+        // As Config::load leaves them for `tenpod --memory 8 service install --set "vm.name=Quest VR"`
+        let cli_options = ["--memory", "8", "--set", "vm.name=Quest VR"].iter().map(|a| a.to_string()).collect();
+        let tenpod = TenPod::new(Config { name: "QuestVR".to_string(), cli_options, ..Config::new() });
+
+        let exe = self_command().unwrap().iter().map(|a| systemd_quote(a)).collect::<Vec<_>>().join(" ");
+        let unit = tenpod.unit_file(false, &["--force".to_string()]).unwrap();
+        assert!(unit.contains("Description=Ten Pod VM (QuestVR)\n"));
+        assert!(unit.contains(&format!("\nExecStart={} start --memory 8 --set \"vm.name=Quest VR\" --force\n", exe)), "{}", unit);
+        assert!(unit.contains(&format!("\nExecStop={} stop --memory 8 --set \"vm.name=Quest VR\"\n", exe)), "{}", unit);
+        assert!(unit.contains("TimeoutStopSec=90\n"));
+        assert!(unit.contains("WantedBy=default.target\n"));
+        assert!(!unit.contains("User="));
+
+        let unit = tenpod.unit_file(true, &[]).unwrap();
+        let uid = {
+            use std::os::unix::fs::MetadataExt;
+            fs::metadata("/proc/self").unwrap().uid()
+        };
+        assert!(unit.contains(&format!("Requires=user-runtime-dir@{}.service\n", uid)));
+        assert!(unit.contains(&format!("Environment=XDG_RUNTIME_DIR=/run/user/{}\n", uid)));
+        assert!(unit.contains("WantedBy=multi-user.target\n"));
+    }
+
+    #[test]
+    fn pci_resolves_driver_modules() {
+        let sys = fake_pci("pci-modules");
+        let pci = PciBus::new(&sys.root);
+
+        assert_eq!(pci.driver_module("xhci_hcd").as_deref(), Some("xhci_pci"));
+        assert_eq!(pci.driver_module("snd_hda_intel").as_deref(), Some("snd_hda_intel"));
+        assert_eq!(pci.driver_module("pcieport"), None);
+        assert_eq!(pci.driver_module("nvidia-gpu"), None);
+    }
+
+    #[test]
+    fn entry_args_revert_per_argument() {
+        let entry = ManifestEntry::EntryArg {
+            path: "/boot/loader/entries/arch.conf".to_string(),
+            arg: "iommu=pt".to_string(),
+        };
+        assert!(ManifestEntry::from_line(&entry.to_line()) == Some(entry));
+
+        let contents = "title Arch Linux\nlinux /vmlinuz-linux\noptions root=UUID=abc rw iommu=pt quiet\n";
+        assert_eq!(
+            remove_entry_option(contents, "iommu=pt"),
+            "title Arch Linux\nlinux /vmlinuz-linux\noptions root=UUID=abc rw quiet\n"
+        );
+        assert_eq!(remove_entry_option(contents, "amd_iommu=on"), contents);
+    }
+
+    /// A VM sized by `config` on the fake_two_ccx() CPUs with `total_gb` of RAM
+    fn sized_vm(sys: &FakeSys, total_gb: u64, config: Config) -> TenPod {
+        sys.write("proc/meminfo", &format!(
+            "MemTotal:       {} kB\nMemFree:         1024 kB\nMemAvailable:   {} kB\n",
+            total_gb * 1024 * 1024 - 200 * 1024, total_gb * 1024 * 1024 / 2
+        ));
+        let mut tenpod = test_vm(Config { numa_node: "off".to_string(), ..config });
+        tenpod.cpus = CpuTopology::new(&sys.root);
+        tenpod.memory = HostMemory::new(sys.root.join("proc"));
+        tenpod
+    }
+
+    #[test]
+    fn suggested_resources_fit_the_host() {
+        let sys = fake_two_ccx("resources");
+
+        // Half of 32GB, and the biggest core count that leaves host.cpus: 7 of 8 cores leave 2 CPUs
+        let tenpod = sized_vm(&sys, 32, Config::new());
+        assert_eq!(tenpod.memory.kb("MemTotal"), Some(32 * 1024 * 1024 - 200 * 1024));
+        assert_eq!(tenpod.suggested_resources().unwrap(), (16, 7));
+        let tenpod = sized_vm(&sys, 32, Config { host_cpus: 6, ..Config::new() });
+        assert_eq!(tenpod.suggested_resources().unwrap(), (16, 5));
+
+        // Never below the 4GB Windows 11 needs, as long as the host keeps its share
+        let tenpod = sized_vm(&sys, 6, Config { host_memory_gb: 2, ..Config::new() });
+        assert_eq!(tenpod.suggested_resources().unwrap().0, 4);
+        let tenpod = sized_vm(&sys, 6, Config::new());
+        assert!(tenpod.suggested_resources().err().unwrap().contains("at least 4GB"));
+
+        let tenpod = sized_vm(&sys, 32, Config { host_cpus: 15, ..Config::new() });
+        assert!(tenpod.suggested_resources().err().unwrap().contains("not even one core"));
+    }
+
+    #[test]
+    fn check_resources_refuses_an_oversized_vm() {
+        let sys = fake_two_ccx("resources-check");
+
+        let tenpod = sized_vm(&sys, 32, Config { memory_gb: 16, cores: 4, ..Config::new() });
+        assert!(tenpod.check_resources().is_ok());
+
+        // 16GB of 16GB: refused, with a size that does fit
+        let tenpod = sized_vm(&sys, 16, Config { memory_gb: 16, cores: 4, ..Config::new() });
+        let error = tenpod.check_resources().err().unwrap();
+        assert!(error.contains("leaves the host"), "{}", error);
+        assert!(error.contains("memory_gb = 8\n  cores = 7"), "{}", error);
+
+        // 7 cores leave the host 2 CPUs, fewer than it asked for
+        let tenpod = sized_vm(&sys, 32, Config { memory_gb: 16, cores: 7, host_cpus: 4, ..Config::new() });
+        let error = tenpod.check_resources().err().unwrap();
+        assert!(error.contains("14 vCPUs leave the host 2 of 16 CPUs, less than host.cpus = 4"), "{}", error);
+        assert!(error.contains("memory_gb = 16\n  cores = 6"), "{}", error);
+
+        // Nothing fits 6GB while keeping 4GB for the host
+        let tenpod = sized_vm(&sys, 6, Config { memory_gb: 4, cores: 2, ..Config::new() });
+        let error = tenpod.check_resources().err().unwrap();
+        assert!(error.contains("No VM size fits this machine"), "{}", error);
+    }
+
+    #[test]
+    fn package_manager_from_os_release() {
+        let detect = |text: &str| PackageManager::from_os_release(text).map(|pm| pm.name());
+
+        assert_eq!(detect("NAME=\"Fedora Linux\"\nID=fedora\n"), Some("dnf"));
+        assert_eq!(detect("ID=ubuntu\nID_LIKE=debian\n"), Some("apt"));
+        assert_eq!(detect("ID=arch\n"), Some("pacman"));
+        assert_eq!(detect("ID=\"opensuse-tumbleweed\"\nID_LIKE=\"opensuse suse\"\n"), Some("zypper"));
+
+        // Derivatives resolve through ID_LIKE
+        assert_eq!(detect("ID=pop\nID_LIKE=\"ubuntu debian\"\n"), Some("apt"));
+        assert_eq!(detect("ID=manjaro\nID_LIKE=arch\n"), Some("pacman"));
+        assert_eq!(detect("ID=nobara\nID_LIKE=\"rhel centos fedora\"\n"), Some("dnf"));
+        assert_eq!(detect("ID=Debian\n"), Some("apt"));
+
+        assert_eq!(detect("ID=gentoo\n"), None);
+        assert_eq!(detect(""), None);
+
+        let apt = PackageManager::Apt.install_command(&["ovmf", "swtpm"]);
+        assert_eq!(apt[..3], ["sh", "-c", r#"apt-get update && apt-get install -y "$@""#]);
+        assert_eq!(apt[4..], ["ovmf", "swtpm"]);
+        assert_eq!(PackageManager::Dnf.install_command(&["swtpm"]), ["dnf", "install", "-y", "swtpm"]);
+    }
+}